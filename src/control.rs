@@ -0,0 +1,90 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{Error, Result};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Notify;
+
+use crate::sync;
+
+#[derive(Clone, Default)]
+pub struct Control {
+    // JSON report of the last build, null before the first one
+    pub status: Arc<Mutex<Value>>,
+    // wakes the watcher up to start a build
+    pub rebuild: Arc<Notify>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+enum Request {
+    Rebuild,
+    Status,
+}
+
+fn handle_request(control: &Control, line: &str) -> Value {
+    match serde_json::from_str::<Request>(line) {
+        Ok(Request::Rebuild) => {
+            control.rebuild.notify_one();
+            json!({ "ok": true })
+        }
+        Ok(Request::Status) => {
+            let build = sync::lock(&control.status).clone();
+            json!({ "ok": true, "build": build })
+        }
+        Err(err) => json!({ "ok": false, "error": err.to_string() }),
+    }
+}
+
+// one JSON request per line, every request gets a single line response
+async fn serve_client(control: Control, stream: UnixStream) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = format!("{}\n", handle_request(&control, &line));
+        if writer.write_all(response.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn serve(control: Control, listener: UnixListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(serve_client(control.clone(), stream));
+            }
+            Err(err) => eprintln!("failed to accept control connection: {}", err),
+        }
+    }
+}
+
+// a socket left by a previous run is replaced, any other file is an error
+fn bind(path: &Path) -> Result<UnixListener> {
+    let stale = fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+    if stale {
+        fs::remove_file(path)?;
+    }
+    let listener = std::os::unix::net::UnixListener::bind(path).map_err(|err| {
+        Error::new(
+            err.kind(),
+            format!("control socket {}: {}", path.display(), err),
+        )
+    })?;
+    listener.set_nonblocking(true)?;
+    UnixListener::from_std(listener)
+}
+
+pub fn run(path: &Path) -> Result<Control> {
+    let listener = bind(path)?;
+    let control = Control::default();
+    tokio::spawn(serve(control.clone(), listener));
+    Ok(control)
+}
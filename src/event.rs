@@ -1,22 +1,90 @@
+use serde::Serialize;
 use std::time::{Duration, Instant};
 use tokio::sync::mpsc::{self, Receiver, Sender};
 
+// (build id, steps, attempts, when the change that triggered the build came)
+pub type BuildData = (u64, Vec<StepData>, u32, Instant);
+
 #[derive(Debug)]
 pub enum ExecutorEvent {
-    Success((u64, Vec<StepData>)),
-    Fail((u64, Vec<StepData>)),
+    // the watcher is up and waits for changes
+    Started(WatchData),
+    // a step (or the configure step) of the current build is spawned
+    StepStarted(String),
+    Success(BuildData),
+    Fail(BuildData),
+    // the build didn't start because its pre-command failed
+    Aborted((u64, StepData)),
+    // the watcher failed, e.g. a watched path vanished
+    Error(String),
+    Shutdown,
 }
 
 #[derive(Clone, Debug)]
 pub struct StepData {
     pub status: bool,
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
     pub start_at: Instant,
     pub stop_at: Instant,
     pub name: String,
+    pub output: String,
+    // (warnings, errors) found in the output, None if they aren't counted
+    pub diagnostics: Option<(usize, usize)>,
+}
+
+// a command as it would be run, without running it
+#[derive(Clone, Debug, Serialize)]
+pub struct PlannedStep {
+    pub name: String,
+    pub command: Vec<String>,
+    pub dir: String,
 }
 
-pub fn make_channel() -> (Sender<ExecutorEvent>, Receiver<ExecutorEvent>) {
-    mpsc::channel(16)
+#[derive(Clone, Debug)]
+pub struct WatchData {
+    pub watch_dirs: Vec<String>,
+    pub plan: Vec<PlannedStep>,
+    pub delay: Option<Duration>,
+    pub extensions: Vec<String>,
+    pub patterns: Vec<String>,
+}
+
+pub fn make_channel(size: usize) -> (Sender<ExecutorEvent>, Receiver<ExecutorEvent>) {
+    mpsc::channel(size)
+}
+
+impl ExecutorEvent {
+    // status and payload of a finished build, None for the service events
+    pub fn get_build(&self) -> Option<(bool, &BuildData)> {
+        match self {
+            ExecutorEvent::Fail(msg) => Some((false, msg)),
+            ExecutorEvent::Success(msg) => Some((true, msg)),
+            ExecutorEvent::Started(_)
+            | ExecutorEvent::StepStarted(_)
+            | ExecutorEvent::Aborted(_)
+            | ExecutorEvent::Error(_)
+            | ExecutorEvent::Shutdown => None,
+        }
+    }
+}
+
+// time from the triggering change to the start of the first step
+pub fn get_latency(steps: &[StepData], triggered_at: Instant) -> Duration {
+    steps
+        .first()
+        .map(|step| step.start_at.saturating_duration_since(triggered_at))
+        .unwrap_or_default()
+}
+
+// wall time of the whole pipeline, parallel steps are counted once
+pub fn get_total_duration(steps: &[StepData]) -> Duration {
+    let start_at = steps.iter().map(|step| step.start_at).min();
+    let stop_at = steps.iter().map(|step| step.stop_at).max();
+    match (start_at, stop_at) {
+        (Some(start_at), Some(stop_at)) => stop_at.saturating_duration_since(start_at),
+        _ => Duration::default(),
+    }
 }
 
 impl StepData {
@@ -1,13 +1,48 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::Gitignore;
+use ignore::{Match, WalkBuilder};
+use std::path::{Path, PathBuf};
 use watchexec::error::RuntimeError;
 use watchexec::filter::Filterer;
 
 use watchexec_events::filekind::{self, ModifyKind};
-use watchexec_events::{Event, Priority, Tag};
+use watchexec_events::{Event, FileType, Priority, Tag};
 
+use tracing::{debug, trace};
 use watchexec_signals::Signal;
 
+// every filtered event is logged with this target at the trace level, see
+// --trace-events
+pub const EVENTS_TARGET: &str = "cppwatch::events";
+
 #[derive(Debug)]
-pub struct ExtenstionsFilter;
+pub struct ExtenstionsFilter {
+    extensions: Vec<String>,
+    // (watch dirs, patterns) matching the sources instead of the extensions
+    patterns: Option<(Vec<PathBuf>, GlobSet)>,
+    gitignore: Option<GitIgnore>,
+    // .cppwatchignore files of the watch dirs, they take precedence over
+    // .gitignore
+    watchignore: Option<GitIgnore>,
+    ignore: Option<GlobSet>,
+    build_files: Option<GlobSet>,
+    // files activating the steps with a trigger, see --trigger
+    trigger_files: Option<GlobSet>,
+    excluded_dirs: Vec<PathBuf>,
+    // changes outside of these dirs are ignored, empty to allow any
+    only_dirs: Vec<PathBuf>,
+    // (watch dirs, max depth)
+    max_depth: Option<(Vec<PathBuf>, usize)>,
+}
+
+const WATCHIGNORE_FILE: &str = ".cppwatchignore";
+
+// All .gitignore files found under the watch directory (and its parents up to
+// the repository root), ordered from the outermost to the innermost one.
+#[derive(Debug, Default)]
+pub struct GitIgnore {
+    matchers: Vec<Gitignore>,
+}
 
 #[derive(Debug)]
 pub struct ModificationFilter;
@@ -21,12 +56,16 @@ pub fn is_process_report(event: &Event) -> bool {
     false
 }
 
+// removed and renamed sources change what compiles as well, temporary files
+// of editors are dropped by the extension filter
 pub fn is_file_modification(event: &Event) -> bool {
     for tag in event.tags.iter() {
         if matches!(
             tag,
             Tag::FileEventKind(filekind::FileEventKind::Modify(ModifyKind::Data(_)))
+                | Tag::FileEventKind(filekind::FileEventKind::Modify(ModifyKind::Name(_)))
                 | Tag::FileEventKind(filekind::FileEventKind::Create(_))
+                | Tag::FileEventKind(filekind::FileEventKind::Remove(_))
         ) {
             return true;
         }
@@ -34,31 +73,330 @@ pub fn is_file_modification(event: &Event) -> bool {
     false
 }
 
-pub fn is_cpp_file(event: &Event) -> bool {
-    for tag in event.tags.iter() {
-        if let Tag::Path { path, .. } = tag {
-            let extension = path
-                .as_os_str()
-                .to_str()
-                .map(|x| x.split('.'))
-                .and_then(|x| x.last())
-                .unwrap_or("");
-            if matches!(extension, "c" | "h" | "cpp" | "hpp" | "cc" | "hh") {
-                return true;
-            }
+// lowercase and without the leading dot, e.g. ".CPP" is "cpp"
+pub fn normalize_extensions(extensions: &[String]) -> Vec<String> {
+    extensions
+        .iter()
+        .map(|x| x.trim().trim_start_matches('.').to_lowercase())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+// the extensions are expected to be normalized
+pub fn has_extension(path: &Path, extensions: &[String]) -> bool {
+    let extension = path
+        .extension()
+        .and_then(|x| x.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    extensions.contains(&extension)
+}
+
+pub fn is_cpp_file(event: &Event, extensions: &[String]) -> bool {
+    event
+        .paths()
+        .any(|(path, _)| has_extension(path, extensions))
+}
+
+// A pattern matches either the whole path or the path relative to one of the
+// roots, e.g. src/**/*.impl
+pub fn is_matching_path(path: &Path, roots: &[PathBuf], globs: &GlobSet) -> bool {
+    globs.is_match(path)
+        || roots
+            .iter()
+            .filter_map(|root| path.strip_prefix(root).ok())
+            .any(|rel| globs.is_match(rel))
+}
+
+pub fn is_matching_file(event: &Event, roots: &[PathBuf], globs: &GlobSet) -> bool {
+    event
+        .paths()
+        .any(|(path, _)| is_matching_path(path, roots, globs))
+}
+
+// build system files are matched by their name, e.g. CMakeLists.txt or *.cmake
+pub fn is_build_path(path: &Path, globs: &GlobSet) -> bool {
+    path.file_name().is_some_and(|name| globs.is_match(name))
+}
+
+pub fn is_build_file(event: &Event, globs: &GlobSet) -> bool {
+    event.paths().any(|(path, _)| is_build_path(path, globs))
+}
+
+// true if the event has paths and every one of them satisfies the predicate
+fn all_paths(event: &Event, predicate: impl Fn(&Path, bool) -> bool) -> bool {
+    let mut paths = event.paths().peekable();
+    if paths.peek().is_none() {
+        return false;
+    }
+    paths.all(|(path, file_type)| predicate(path, matches!(file_type, Some(FileType::Dir))))
+}
+
+pub fn is_ignored(event: &Event, gitignore: &GitIgnore) -> bool {
+    all_paths(event, |path, is_dir| gitignore.is_ignored(path, is_dir))
+}
+
+// a negated pattern, e.g. !generated.h, re-includes the file
+pub fn is_whitelisted(event: &Event, gitignore: &GitIgnore) -> bool {
+    all_paths(event, |path, is_dir| {
+        gitignore.matched(path, is_dir) == Some(false)
+    })
+}
+
+pub fn is_excluded(event: &Event, globs: &GlobSet) -> bool {
+    all_paths(event, |path, _| globs.is_match(path))
+}
+
+pub fn is_in_dirs(event: &Event, dirs: &[PathBuf]) -> bool {
+    all_paths(event, |path, _| {
+        dirs.iter().any(|dir| path.starts_with(dir))
+    })
+}
+
+pub fn is_outside_dirs(event: &Event, dirs: &[PathBuf]) -> bool {
+    all_paths(event, |path, _| {
+        !dirs.iter().any(|dir| path.starts_with(dir))
+    })
+}
+
+// Depth 0 is a file right in one of the roots. Paths outside of the roots
+// aren't limited.
+pub fn is_too_deep(event: &Event, roots: &[PathBuf], depth: usize) -> bool {
+    all_paths(event, |path, _| {
+        roots
+            .iter()
+            .filter_map(|root| path.strip_prefix(root).ok())
+            .map(|rel| rel.components().count())
+            .min()
+            .is_some_and(|count| count > depth + 1)
+    })
+}
+
+pub fn make_globset(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns.iter() {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build()
+}
+
+// .gitignore files that may affect the root, from the outermost to the innermost
+fn find_gitignore_files(root: &Path) -> Vec<PathBuf> {
+    let root = root.canonicalize().unwrap_or(root.to_path_buf());
+    let mut files = Vec::new();
+
+    // parent directories up to the repository root
+    for dir in root.ancestors().skip(1) {
+        files.push(dir.join(".gitignore"));
+        if dir.join(".git").exists() {
+            break;
         }
     }
-    false
+    files.reverse();
+
+    // the walker skips ignored directories, so nested build trees are not
+    // scanned at all
+    let walker = WalkBuilder::new(&root)
+        .hidden(false)
+        .require_git(false)
+        .build();
+    files.extend(
+        walker
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name() == ".gitignore")
+            .map(|entry| entry.into_path()),
+    );
+    files
 }
 
 pub fn is_stop_signal(signal: &Signal) -> bool {
     matches!(signal, Signal::Interrupt | Signal::Terminate)
 }
 
+impl ExtenstionsFilter {
+    pub fn new(extensions: &[String]) -> ExtenstionsFilter {
+        let extensions = normalize_extensions(extensions);
+        ExtenstionsFilter {
+            extensions,
+            patterns: None,
+            gitignore: None,
+            watchignore: None,
+            ignore: None,
+            build_files: None,
+            trigger_files: None,
+            excluded_dirs: Vec::new(),
+            only_dirs: Vec::new(),
+            max_depth: None,
+        }
+    }
+
+    pub fn with_excluded_dirs(mut self, dirs: Vec<PathBuf>) -> ExtenstionsFilter {
+        self.excluded_dirs = dirs;
+        self
+    }
+
+    pub fn with_only_dirs(mut self, dirs: Vec<PathBuf>) -> ExtenstionsFilter {
+        self.only_dirs = dirs;
+        self
+    }
+
+    pub fn with_patterns(mut self, roots: Vec<PathBuf>, globs: GlobSet) -> ExtenstionsFilter {
+        self.patterns = Some((roots, globs));
+        self
+    }
+
+    pub fn with_max_depth(mut self, roots: Vec<PathBuf>, depth: usize) -> ExtenstionsFilter {
+        self.max_depth = Some((roots, depth));
+        self
+    }
+
+    pub fn with_build_files(mut self, globs: GlobSet) -> ExtenstionsFilter {
+        self.build_files = Some(globs);
+        self
+    }
+
+    pub fn with_trigger_files(mut self, globs: GlobSet) -> ExtenstionsFilter {
+        self.trigger_files = Some(globs);
+        self
+    }
+
+    pub fn with_ignore(mut self, globs: GlobSet) -> ExtenstionsFilter {
+        self.ignore = Some(globs);
+        self
+    }
+
+    pub fn with_gitignore(mut self, gitignore: GitIgnore) -> ExtenstionsFilter {
+        self.gitignore = Some(gitignore);
+        self
+    }
+
+    pub fn with_watchignore(mut self, watchignore: GitIgnore) -> ExtenstionsFilter {
+        self.watchignore = Some(watchignore);
+        self
+    }
+
+    // why the event is accepted (Ok) or rejected (Err)
+    fn check_reason(&self, event: &Event) -> Result<&'static str, &'static str> {
+        if is_process_report(event) {
+            return Ok("process report");
+        }
+        if !is_file_modification(event) {
+            return Err("not a modification");
+        }
+        if is_in_dirs(event, &self.excluded_dirs) {
+            return Err("excluded dir");
+        }
+        if !self.only_dirs.is_empty() && is_outside_dirs(event, &self.only_dirs) {
+            return Err("outside of --only");
+        }
+        if let Some((roots, depth)) = &self.max_depth {
+            if is_too_deep(event, roots, *depth) {
+                return Err("too deep");
+            }
+        }
+        if let Some(globs) = &self.ignore {
+            if is_excluded(event, globs) {
+                return Err("ignore pattern");
+            }
+        }
+        let mut whitelisted = false;
+        if let Some(watchignore) = &self.watchignore {
+            if is_ignored(event, watchignore) {
+                return Err("cppwatchignore");
+            }
+            whitelisted = is_whitelisted(event, watchignore);
+        }
+        if let Some(gitignore) = self.gitignore.as_ref().filter(|_| !whitelisted) {
+            if is_ignored(event, gitignore) {
+                return Err("gitignored");
+            }
+        }
+        match &self.patterns {
+            Some((roots, globs)) if is_matching_file(event, roots, globs) => return Ok("pattern"),
+            None if is_cpp_file(event, &self.extensions) => return Ok("extension"),
+            _ => {}
+        }
+        if let Some(globs) = &self.build_files {
+            if is_build_file(event, globs) {
+                return Ok("build file");
+            }
+        }
+        if let Some(globs) = &self.trigger_files {
+            if is_build_file(event, globs) {
+                return Ok("trigger file");
+            }
+        }
+        match self.patterns {
+            Some(_) => Err("no matching pattern"),
+            None => Err("no matching extension"),
+        }
+    }
+}
+
+impl GitIgnore {
+    pub fn new<P: AsRef<Path>>(roots: &[P]) -> GitIgnore {
+        let mut files = Vec::new();
+        for root in roots.iter() {
+            for file in find_gitignore_files(root.as_ref()) {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+
+        GitIgnore::from_files(&files)
+    }
+
+    // .cppwatchignore right in the watch dirs, with the gitignore syntax
+    pub fn watchignore<P: AsRef<Path>>(roots: &[P]) -> GitIgnore {
+        let files: Vec<PathBuf> = roots
+            .iter()
+            .map(|root| root.as_ref().join(WATCHIGNORE_FILE))
+            .collect();
+        GitIgnore::from_files(&files)
+    }
+
+    fn from_files(files: &[PathBuf]) -> GitIgnore {
+        let matchers = files
+            .iter()
+            .filter(|path| path.is_file())
+            .map(|path| Gitignore::new(path).0)
+            .collect();
+        GitIgnore { matchers }
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        path.components().any(|x| x.as_os_str() == ".git")
+            || self.matched(path, is_dir) == Some(true)
+    }
+
+    // Some(true) for an ignored path, Some(false) for a re-included one and
+    // None if no pattern matches it
+    pub fn matched(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        // inner files override the outer ones
+        let mut ignored = None;
+        for matcher in self.matchers.iter() {
+            if !path.starts_with(matcher.path()) {
+                continue;
+            }
+            match matcher.matched_path_or_any_parents(path, is_dir) {
+                Match::Ignore(_) => ignored = Some(true),
+                Match::Whitelist(_) => ignored = Some(false),
+                Match::None => {}
+            }
+        }
+        ignored
+    }
+}
+
 impl Filterer for ExtenstionsFilter {
     fn check_event(&self, event: &Event, _priority: Priority) -> Result<bool, RuntimeError> {
-        let result =
-            is_process_report(event) || (is_file_modification(event) && is_cpp_file(event));
+        let (result, reason) = match self.check_reason(event) {
+            Ok(reason) => (true, reason),
+            Err(reason) => (false, reason),
+        };
+        debug!(%event, reason, passed = result, "filtered");
+        trace!(target: EVENTS_TARGET, %event, accepted = result, reason);
         Ok(result)
     }
 }
@@ -0,0 +1,10 @@
+// The build-on-change loop as a library: the watcher runs the steps and sends
+// an ExecutorEvent for every finished build, the reporter is one of the
+// possible consumers of these events.
+#[cfg(all(unix, feature = "control"))]
+pub mod control;
+pub mod event;
+pub mod filters;
+pub mod reporter;
+mod sync;
+pub mod watcher;
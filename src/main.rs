@@ -1,54 +1,917 @@
-mod event;
-mod filters;
-mod reporter;
-mod watcher;
-
-use clap::Parser;
-use std::io::Result;
+use clap::{Parser, ValueEnum};
+#[cfg(all(unix, feature = "control"))]
+use cppwatch::control;
+use cppwatch::{event, filters, reporter, watcher};
+use serde::Deserialize;
+use std::io::{Error, ErrorKind, IsTerminal, Result};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+
+const CONFIG_FILE: &str = "cppwatch.toml";
+const DEFAULT_WATCH_DIR: &str = ".";
+const DEFAULT_BUILD_COMMAND: &str = "make -j4";
+const DEFAULT_TEST_COMMAND: &str = "make test";
+const DEFAULT_BUILD_NAME: &str = "Build";
+const DEFAULT_TEST_NAME: &str = "Test";
+const DEFAULT_TIMESTAMP_FORMAT: &str = "%H:%M:%S";
+const DEFAULT_EXTENSIONS: &[&str] = &["c", "h", "cpp", "hpp", "cc", "hh"];
+const DEFAULT_QUEUE_SIZE: usize = 16;
+const DEFAULT_RATIO_THRESHOLDS: (u64, u64) = (50, 80);
+const DEFAULT_WARNING_PATTERNS: &[&str] = &[r"\bwarning:"];
+const DEFAULT_ERROR_PATTERNS: &[&str] = &[r"\berror:"];
+const DEFAULT_BUILD_FILES: &[&str] = &["CMakeLists.txt", "Makefile", "*.cmake", "meson.build"];
+
+#[derive(Clone, Copy, Debug, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum ColorMode {
+    Always,
+    // colors on a terminal
+    Auto,
+    Never,
+}
 
-#[derive(Parser, Debug)]
+// Every option is optional here to tell apart values given on the command
+// line from the ones loaded from the config file. Defaults are applied after
+// both sources are merged. Flags take an optional value as well, so e.g.
+// --no-notify=false turns off `no_notify = true` from the file.
+#[derive(Parser, Deserialize, Debug, Default)]
 #[command(author, version, about, long_about = None)]
+#[serde(default, deny_unknown_fields)]
 struct Args {
     #[arg(index = 1)]
-    watch_dir: String,
+    watch_dirs: Vec<String>,
+
+    #[arg(long)]
+    #[serde(skip)]
+    config: Option<String>,
+
+    #[arg(long)]
+    build_dir: Option<String>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    create_build_dir: Option<bool>,
+
+    #[arg(short, long)]
+    build_command: Option<String>,
+
+    #[arg(long, conflicts_with = "build_command")]
+    build_script: Option<PathBuf>,
+
+    #[arg(short, long)]
+    test_command: Option<String>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    no_test: Option<bool>,
+
+    #[arg(long)]
+    build_name: Option<String>,
+
+    #[arg(long)]
+    test_name: Option<String>,
+
+    #[arg(long = "step", value_parser = parse_step)]
+    steps: Option<Vec<(String, String)>>,
+
+    #[arg(long = "step-dir", value_parser = parse_step_dir)]
+    step_dirs: Option<Vec<(String, String)>>,
+
+    #[arg(long)]
+    test_dir: Option<String>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    build_in_build_dir: Option<bool>,
+
+    #[arg(long = "trigger", value_parser = parse_trigger)]
+    triggers: Option<Vec<(String, Vec<String>)>>,
+
+    #[arg(long, value_delimiter = ',')]
+    parallel_steps: Option<Vec<String>>,
+
+    #[arg(long)]
+    pre_command: Option<String>,
+
+    #[arg(long)]
+    post_command: Option<String>,
+
+    #[arg(long)]
+    run_command: Option<String>,
+
+    #[arg(long)]
+    configure_command: Option<String>,
+
+    #[arg(long)]
+    shell: Option<String>,
+
+    #[arg(short, long)]
+    delay: Option<u64>,
+
+    #[arg(long)]
+    build_delay: Option<u64>,
+
+    #[arg(long)]
+    test_delay: Option<u64>,
+
+    #[arg(long)]
+    min_interval: Option<u64>,
+
+    #[arg(long)]
+    timeout: Option<u64>,
+
+    #[arg(long)]
+    retries: Option<u32>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    continue_on_fail: Option<bool>,
+
+    // --fail-fast, --fail-fast=false, the inverse of --continue-on-fail
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        conflicts_with = "continue_on_fail"
+    )]
+    fail_fast: Option<bool>,
+
+    #[arg(long, value_parser = parse_env)]
+    env: Option<Vec<(String, String)>>,
+
+    #[arg(long)]
+    env_file: Option<PathBuf>,
+
+    #[arg(long, allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+    nice: Option<i32>,
+
+    #[arg(short, long, value_delimiter = ',')]
+    extensions: Option<Vec<String>>,
+
+    #[arg(long, value_delimiter = ',')]
+    extra_extensions: Option<Vec<String>>,
+
+    #[arg(long = "pattern")]
+    patterns: Option<Vec<String>>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    watch_build_files: Option<bool>,
+
+    #[arg(long, value_delimiter = ',')]
+    build_files: Option<Vec<String>>,
+
+    #[arg(long)]
+    max_depth: Option<usize>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    follow_symlinks: Option<bool>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    no_gitignore: Option<bool>,
+
+    #[arg(long)]
+    ignore: Option<Vec<String>>,
+
+    #[arg(long)]
+    only: Option<Vec<String>>,
 
-    #[arg(long, default_value = "")]
-    build_dir: String,
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    no_notify: Option<bool>,
 
-    #[arg(short, long, default_value = "make -j4")]
-    build_command: String,
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    notify_on_change: Option<bool>,
 
-    #[arg(short, long, default_value = "make test")]
-    test_command: String,
+    #[arg(long, value_enum)]
+    notify_urgency: Option<reporter::NotifyUrgency>,
 
-    #[arg(short, long, default_value = "0")]
-    delay: String,
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    rich_notify: Option<bool>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    bell: Option<bool>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    bell_on_success: Option<bool>,
+
+    #[arg(long)]
+    sound_success: Option<PathBuf>,
+
+    #[arg(long)]
+    sound_failure: Option<PathBuf>,
+
+    #[arg(long)]
+    icon_success: Option<String>,
+
+    #[arg(long)]
+    icon_failure: Option<String>,
+
+    #[arg(long, value_enum)]
+    format: Option<reporter::Format>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    no_color: Option<bool>,
+
+    #[arg(long, value_enum)]
+    color: Option<ColorMode>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    clear: Option<bool>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    progress: Option<bool>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    stderr_status: Option<bool>,
+
+    #[arg(long)]
+    timestamp_format: Option<String>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    skip_duplicate_reports: Option<bool>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    show_output: Option<bool>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    stream: Option<bool>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    count_warnings: Option<bool>,
+
+    #[arg(long = "warning-pattern")]
+    warning_patterns: Option<Vec<String>>,
+
+    #[arg(long = "error-pattern")]
+    error_patterns: Option<Vec<String>>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    initial_build: Option<bool>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    once: Option<bool>,
+
+    #[arg(long)]
+    max_builds: Option<u64>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    dry_run: Option<bool>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    list_defaults: Option<bool>,
+
+    #[arg(long)]
+    history_file: Option<PathBuf>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    reset_stats: Option<bool>,
+
+    #[arg(long)]
+    csv: Option<PathBuf>,
+
+    #[arg(long)]
+    delta_threshold: Option<u64>,
+
+    #[arg(long)]
+    delta_red_threshold: Option<u64>,
+
+    #[arg(long, value_parser = parse_ratio_thresholds)]
+    ratio_thresholds: Option<(u64, u64)>,
+
+    #[arg(long)]
+    metrics_addr: Option<String>,
+
+    #[arg(long)]
+    webhook: Option<String>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    webhook_on_fail_only: Option<bool>,
+
+    #[arg(long)]
+    queue_size: Option<usize>,
+
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+
+    #[arg(short, long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    verbose: Option<bool>,
+
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    trace_events: Option<bool>,
+
+    #[arg(short, long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    quiet: Option<bool>,
 }
 
-fn read_delay(args: &Args) -> Option<Duration> {
-    if args.delay.is_empty() {
-        None
+impl Args {
+    fn from_file(path: &str) -> Result<Args> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| Error::new(err.kind(), format!("{}: {}", path, err)))?;
+        toml::from_str(&text)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, format!("{}: {}", path, err)))
+    }
+
+    fn load() -> Result<Args> {
+        let args = Args::parse();
+        let path = match &args.config {
+            Some(path) => Some(path.clone()),
+            None => {
+                let watch_dir = args
+                    .watch_dirs
+                    .first()
+                    .map(|x| x.as_str())
+                    .unwrap_or(DEFAULT_WATCH_DIR);
+                let path = Path::new(watch_dir).join(CONFIG_FILE);
+                path.is_file().then(|| path.to_string_lossy().into_owned())
+            }
+        };
+
+        match path {
+            Some(path) => Ok(args.merge(Args::from_file(&path)?)),
+            None => Ok(args),
+        }
+    }
+
+    // values already set in self (command line) win over the file ones
+    fn merge(self, file: Args) -> Args {
+        // the command line wins over the file whichever of the two is used
+        let continue_on_fail = read_continue_on_fail(&self).or(read_continue_on_fail(&file));
+        Args {
+            watch_dirs: if self.watch_dirs.is_empty() {
+                file.watch_dirs
+            } else {
+                self.watch_dirs
+            },
+            config: self.config,
+            build_dir: self.build_dir.or(file.build_dir),
+            create_build_dir: self.create_build_dir.or(file.create_build_dir),
+            build_command: self.build_command.or(file.build_command),
+            build_script: self.build_script.or(file.build_script),
+            test_command: self.test_command.or(file.test_command),
+            no_test: self.no_test.or(file.no_test),
+            build_name: self.build_name.or(file.build_name),
+            test_name: self.test_name.or(file.test_name),
+            steps: self.steps.or(file.steps),
+            step_dirs: self.step_dirs.or(file.step_dirs),
+            test_dir: self.test_dir.or(file.test_dir),
+            build_in_build_dir: self.build_in_build_dir.or(file.build_in_build_dir),
+            triggers: self.triggers.or(file.triggers),
+            parallel_steps: self.parallel_steps.or(file.parallel_steps),
+            pre_command: self.pre_command.or(file.pre_command),
+            post_command: self.post_command.or(file.post_command),
+            run_command: self.run_command.or(file.run_command),
+            configure_command: self.configure_command.or(file.configure_command),
+            shell: self.shell.or(file.shell),
+            delay: self.delay.or(file.delay),
+            build_delay: self.build_delay.or(file.build_delay),
+            test_delay: self.test_delay.or(file.test_delay),
+            min_interval: self.min_interval.or(file.min_interval),
+            timeout: self.timeout.or(file.timeout),
+            retries: self.retries.or(file.retries),
+            continue_on_fail,
+            fail_fast: None,
+            env: self.env.or(file.env),
+            env_file: self.env_file.or(file.env_file),
+            nice: self.nice.or(file.nice),
+            extensions: self.extensions.or(file.extensions),
+            extra_extensions: self.extra_extensions.or(file.extra_extensions),
+            patterns: self.patterns.or(file.patterns),
+            watch_build_files: self.watch_build_files.or(file.watch_build_files),
+            build_files: self.build_files.or(file.build_files),
+            max_depth: self.max_depth.or(file.max_depth),
+            follow_symlinks: self.follow_symlinks.or(file.follow_symlinks),
+            no_gitignore: self.no_gitignore.or(file.no_gitignore),
+            ignore: self.ignore.or(file.ignore),
+            only: self.only.or(file.only),
+            no_notify: self.no_notify.or(file.no_notify),
+            notify_on_change: self.notify_on_change.or(file.notify_on_change),
+            notify_urgency: self.notify_urgency.or(file.notify_urgency),
+            rich_notify: self.rich_notify.or(file.rich_notify),
+            bell: self.bell.or(file.bell),
+            bell_on_success: self.bell_on_success.or(file.bell_on_success),
+            sound_success: self.sound_success.or(file.sound_success),
+            sound_failure: self.sound_failure.or(file.sound_failure),
+            icon_success: self.icon_success.or(file.icon_success),
+            icon_failure: self.icon_failure.or(file.icon_failure),
+            format: self.format.or(file.format),
+            no_color: self.no_color.or(file.no_color),
+            color: self.color.or(file.color),
+            clear: self.clear.or(file.clear),
+            progress: self.progress.or(file.progress),
+            stderr_status: self.stderr_status.or(file.stderr_status),
+            timestamp_format: self.timestamp_format.or(file.timestamp_format),
+            skip_duplicate_reports: self.skip_duplicate_reports.or(file.skip_duplicate_reports),
+            show_output: self.show_output.or(file.show_output),
+            stream: self.stream.or(file.stream),
+            count_warnings: self.count_warnings.or(file.count_warnings),
+            warning_patterns: self.warning_patterns.or(file.warning_patterns),
+            error_patterns: self.error_patterns.or(file.error_patterns),
+            initial_build: self.initial_build.or(file.initial_build),
+            once: self.once.or(file.once),
+            max_builds: self.max_builds.or(file.max_builds),
+            dry_run: self.dry_run.or(file.dry_run),
+            list_defaults: self.list_defaults.or(file.list_defaults),
+            history_file: self.history_file.or(file.history_file),
+            reset_stats: self.reset_stats.or(file.reset_stats),
+            csv: self.csv.or(file.csv),
+            delta_threshold: self.delta_threshold.or(file.delta_threshold),
+            delta_red_threshold: self.delta_red_threshold.or(file.delta_red_threshold),
+            ratio_thresholds: self.ratio_thresholds.or(file.ratio_thresholds),
+            metrics_addr: self.metrics_addr.or(file.metrics_addr),
+            webhook: self.webhook.or(file.webhook),
+            webhook_on_fail_only: self.webhook_on_fail_only.or(file.webhook_on_fail_only),
+            queue_size: self.queue_size.or(file.queue_size),
+            control_socket: self.control_socket.or(file.control_socket),
+            verbose: self.verbose.or(file.verbose),
+            trace_events: self.trace_events.or(file.trace_events),
+            quiet: self.quiet.or(file.quiet),
+        }
+    }
+}
+
+// parses extra steps given as NAME=COMMAND
+fn parse_step(input: &str) -> std::result::Result<(String, String), String> {
+    input
+        .split_once('=')
+        .map(|(name, cmd)| (name.trim().to_owned(), cmd.trim().to_owned()))
+        .filter(|(name, _)| !name.is_empty())
+        .ok_or(format!("expected NAME=COMMAND, got '{}'", input))
+}
+
+// parses working dirs of steps given as NAME=DIR
+fn parse_step_dir(input: &str) -> std::result::Result<(String, String), String> {
+    input
+        .split_once('=')
+        .map(|(name, dir)| (name.trim().to_owned(), dir.trim().to_owned()))
+        .filter(|(name, dir)| !name.is_empty() && !dir.is_empty())
+        .ok_or(format!("expected NAME=DIR, got '{}'", input))
+}
+
+// parses the pass ratios the colors change at given as YELLOW,GREEN
+fn parse_ratio_thresholds(input: &str) -> std::result::Result<(u64, u64), String> {
+    input
+        .split_once(',')
+        .and_then(|(yellow, green)| Some((yellow.trim().parse().ok()?, green.trim().parse().ok()?)))
+        .ok_or(format!("expected YELLOW,GREEN, got '{}'", input))
+}
+
+// parses the files activating a step given as NAME=PATTERN[,PATTERN...]
+fn parse_trigger(input: &str) -> std::result::Result<(String, Vec<String>), String> {
+    input
+        .split_once('=')
+        .map(|(name, patterns)| {
+            let patterns = patterns
+                .split(',')
+                .map(|x| x.trim().to_owned())
+                .filter(|x| !x.is_empty())
+                .collect::<Vec<_>>();
+            (name.trim().to_owned(), patterns)
+        })
+        .filter(|(name, patterns)| !name.is_empty() && !patterns.is_empty())
+        .ok_or(format!(
+            "expected NAME=PATTERN[,PATTERN...], got '{}'",
+            input
+        ))
+}
+
+// parses environment variables given as KEY=VALUE
+fn parse_env(input: &str) -> std::result::Result<(String, String), String> {
+    input
+        .split_once('=')
+        .map(|(key, value)| (key.trim().to_owned(), value.to_owned()))
+        .filter(|(key, _)| !key.is_empty())
+        .ok_or(format!("expected KEY=VALUE, got '{}'", input))
+}
+
+// A dotenv file: KEY=VALUE lines, optionally quoted or prefixed with export,
+// comments and blank lines are skipped
+fn read_env_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+    let mut vars = Vec::new();
+    for (num, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = parse_env(line).map_err(|err| {
+            let txt = format!("{}:{}: {}", path.display(), num + 1, err);
+            Error::new(ErrorKind::InvalidData, txt)
+        })?;
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+            .unwrap_or(value);
+        vars.push((key, value.to_owned()));
+    }
+    Ok(vars)
+}
+
+// the variables of --env are set after the file ones, so they override them
+fn read_env(args: &Args) -> Result<Vec<(String, String)>> {
+    let mut vars = match &args.env_file {
+        Some(path) => read_env_file(path)?,
+        None => Vec::new(),
+    };
+    vars.extend(args.env.clone().unwrap_or_default());
+    Ok(vars)
+}
+
+// A script runs with the interpreter from its shebang line (with its optional
+// argument) or with sh, so it doesn't need to be executable
+fn read_script_command(path: &Path) -> Result<String> {
+    let path = path
+        .canonicalize()
+        .map_err(|err| Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+    let text = std::fs::read(&path)?;
+    let first_line = text.split(|x| *x == b'\n').next().unwrap_or_default();
+    let first_line = String::from_utf8_lossy(first_line);
+    let mut command: Vec<String> = match first_line.strip_prefix("#!") {
+        Some(shebang) => match shebang.trim().split_once(char::is_whitespace) {
+            Some((interpreter, arg)) => vec![interpreter.to_owned(), arg.trim().to_owned()],
+            None => vec![shebang.trim().to_owned()],
+        },
+        None => vec!["sh".to_owned()],
+    };
+    command.push(path.to_string_lossy().into_owned());
+    Ok(shell_words::join(command))
+}
+
+fn read_steps(args: &Args) -> Result<Vec<(String, String)>> {
+    let build_command = match &args.build_script {
+        Some(path) => read_script_command(path)?,
+        None => args
+            .build_command
+            .clone()
+            .unwrap_or(DEFAULT_BUILD_COMMAND.to_owned()),
+    };
+    let test_command = args
+        .test_command
+        .clone()
+        .unwrap_or(DEFAULT_TEST_COMMAND.to_owned());
+    let build_name = args
+        .build_name
+        .clone()
+        .unwrap_or(DEFAULT_BUILD_NAME.to_owned());
+    let test_name = args
+        .test_name
+        .clone()
+        .unwrap_or(DEFAULT_TEST_NAME.to_owned());
+    let mut steps = vec![(build_name, build_command)];
+    if !args.no_test.unwrap_or_default() {
+        steps.push((test_name, test_command));
+    }
+    steps.extend(args.steps.clone().unwrap_or_default());
+    Ok(steps)
+}
+
+// --test-dir is a shorthand for --step-dir of the test step and
+// --build-in-build-dir=false for --step-dir of the build step set to the watch
+// dir, --step-dir wins over both
+fn read_step_dirs(args: &Args) -> Vec<(String, String)> {
+    let mut step_dirs = args.step_dirs.clone().unwrap_or_default();
+    if let Some(dir) = &args.test_dir {
+        let test_name = args.test_name.clone();
+        let test_name = test_name.unwrap_or(DEFAULT_TEST_NAME.to_owned());
+        step_dirs.push((test_name, dir.clone()));
+    }
+    if args.build_in_build_dir == Some(false) {
+        let build_name = args.build_name.clone();
+        let build_name = build_name.unwrap_or(DEFAULT_BUILD_NAME.to_owned());
+        // relative step dirs are resolved against the watch dir
+        step_dirs.push((build_name, ".".to_owned()));
+    }
+    step_dirs
+}
+
+fn read_watch_dirs(args: &Args) -> Vec<String> {
+    if args.watch_dirs.is_empty() {
+        vec![DEFAULT_WATCH_DIR.to_owned()]
     } else {
-        args.delay.parse::<u64>().ok().map(Duration::from_secs)
+        args.watch_dirs.clone()
+    }
+}
+
+// --no-color is the same as --color never
+fn read_color_mode(args: &Args) -> ColorMode {
+    match args.no_color.unwrap_or_default() {
+        true => ColorMode::Never,
+        false => args.color.unwrap_or(ColorMode::Auto),
+    }
+}
+
+// by default colors are off via NO_COLOR, on a dumb terminal or when the
+// output isn't a tty
+fn read_color(args: &Args, is_terminal: bool) -> bool {
+    match read_color_mode(args) {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        ColorMode::Auto => {
+            let no_color_env = std::env::var_os("NO_COLOR").is_some_and(|x| !x.is_empty());
+            let dumb = std::env::var_os("TERM").is_some_and(|x| x == "dumb");
+            !no_color_env && !dumb && is_terminal
+        }
+    }
+}
+
+// --quiet is a shorthand for the compact format
+fn read_format(args: &Args) -> reporter::Format {
+    match args.format {
+        Some(format) => format,
+        None if args.quiet.unwrap_or_default() => reporter::Format::Compact,
+        None => reporter::Format::default(),
+    }
+}
+
+// clearing makes sense only for a text report shown on a tty
+fn read_clear(args: &Args) -> bool {
+    let text = read_format(args) == reporter::Format::Text;
+    args.clear.unwrap_or_default() && text && std::io::stdout().is_terminal()
+}
+
+// the progress line is redrawn in place, so it needs a tty as well
+fn read_progress(args: &Args) -> bool {
+    let text = read_format(args) != reporter::Format::Json;
+    args.progress.unwrap_or_default() && text && std::io::stderr().is_terminal()
+}
+
+// --delay is the default for both the build and the test delay
+fn read_delay(args: &Args) -> Option<Duration> {
+    args.build_delay.or(args.delay).map(Duration::from_secs)
+}
+
+fn read_step_delays(args: &Args) -> Vec<(String, Duration)> {
+    match args.test_delay.or(args.delay) {
+        Some(delay) if !args.no_test.unwrap_or_default() => {
+            let test_name = args.test_name.clone();
+            let test_name = test_name.unwrap_or(DEFAULT_TEST_NAME.to_owned());
+            vec![(test_name, Duration::from_secs(delay))]
+        }
+        _ => Vec::new(),
+    }
+}
+
+// the events of finished builds wait in the queue while the reporter is busy
+fn read_queue_size(args: &Args) -> Result<usize> {
+    match args.queue_size.unwrap_or(DEFAULT_QUEUE_SIZE) {
+        0 => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "queue size must be at least 1",
+        )),
+        size => Ok(size),
     }
 }
 
+// the file values aren't checked by the parser
+fn read_ratio_thresholds(args: &Args) -> Result<(u64, u64)> {
+    match args.ratio_thresholds.unwrap_or(DEFAULT_RATIO_THRESHOLDS) {
+        (yellow, green) if yellow < green && green <= 100 => Ok((yellow, green)),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "ratio thresholds must be yellow < green <= 100",
+        )),
+    }
+}
+
+// the shell with its arguments, e.g. "bash -c", the command is appended to them
+fn read_shell(args: &Args) -> Result<Vec<String>> {
+    let Some(shell) = &args.shell else {
+        return Ok(Vec::new());
+    };
+    match shell_words::split(shell) {
+        Ok(words) if !words.is_empty() => Ok(words),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid shell '{}'", shell),
+        )),
+    }
+}
+
+fn read_configure(args: &Args) -> Option<String> {
+    args.configure_command.clone().filter(|cmd| !cmd.is_empty())
+}
+
+// the extra extensions are added to the default or the given ones
+fn read_extensions(args: &Args) -> Vec<String> {
+    let mut extensions = args
+        .extensions
+        .clone()
+        .unwrap_or_else(|| DEFAULT_EXTENSIONS.iter().map(|x| x.to_string()).collect());
+    for extension in args.extra_extensions.iter().flatten() {
+        if !extensions.contains(extension) {
+            extensions.push(extension.clone());
+        }
+    }
+    extensions
+}
+
+// --fail-fast is the inverse of --continue-on-fail, None if neither is set
+fn read_continue_on_fail(args: &Args) -> Option<bool> {
+    args.fail_fast.map(|x| !x).or(args.continue_on_fail)
+}
+
+// warnings are counted when asked to or when the patterns are given
+fn read_count_warnings(args: &Args) -> bool {
+    args.count_warnings.unwrap_or_default()
+        || args.warning_patterns.is_some()
+        || args.error_patterns.is_some()
+}
+
+fn read_patterns(patterns: &Option<Vec<String>>, defaults: &[&str]) -> Vec<String> {
+    patterns
+        .clone()
+        .unwrap_or_else(|| defaults.iter().map(|x| x.to_string()).collect())
+}
+
+// build files are watched when asked to, when their names are given or when
+// they have to be configured
+fn read_build_files(args: &Args) -> Vec<String> {
+    if !args.watch_build_files.unwrap_or_default()
+        && args.build_files.is_none()
+        && read_configure(args).is_none()
+    {
+        return Vec::new();
+    }
+    args.build_files
+        .clone()
+        .unwrap_or_else(|| DEFAULT_BUILD_FILES.iter().map(|x| x.to_string()).collect())
+}
+
+// The process fails if the most recent build failed. A single run (--once)
+// without any build is a failure as well.
+fn is_failed(last_status: Option<bool>, once: bool) -> bool {
+    match last_status {
+        Some(status) => !status,
+        None => once,
+    }
+}
+
+// debug logs of cppwatch itself go to stderr, nothing is logged by default
+fn init_logging(args: &Args) {
+    let mut directives = Vec::new();
+    if args.verbose.unwrap_or_default() {
+        directives.push("cppwatch=debug".to_owned());
+    }
+    if args.trace_events.unwrap_or_default() {
+        directives.push(format!("{}=trace", filters::EVENTS_TARGET));
+    }
+    if !directives.is_empty() {
+        tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::new(directives.join(",")))
+            .with_writer(std::io::stderr)
+            .with_ansi(read_color(args, std::io::stderr().is_terminal()))
+            .init();
+    }
+}
+
+// errors are printed as messages rather than in their Debug form
 #[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+async fn main() {
+    if let Err(err) = run().await {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> Result<()> {
+    let args = Args::load()?;
+    init_logging(&args);
     let delay = read_delay(&args);
-    let (tx, rx) = event::make_channel();
+    let step_delays = read_step_delays(&args);
+    let step_dirs = read_step_dirs(&args);
+    let extensions = read_extensions(&args);
+    let build_files = read_build_files(&args);
+    if args.list_defaults.unwrap_or_default() {
+        // what triggers a build, after merging the config file
+        for (key, values) in [("extensions:", &extensions), ("build_files:", &build_files)] {
+            let line = std::iter::once(key).chain(values.iter().map(|x| x.as_str()));
+            println!("{}", line.collect::<Vec<_>>().join(" "));
+        }
+        return Ok(());
+    }
+    let configure_command = read_configure(&args);
+    let steps = read_steps(&args)?;
+    let shell = read_shell(&args)?;
+    let ratio_thresholds = read_ratio_thresholds(&args)?;
+    let count_warnings = read_count_warnings(&args);
+    let continue_on_fail = read_continue_on_fail(&args).unwrap_or_default();
+    let env = read_env(&args)?;
+    let watch_dirs = read_watch_dirs(&args);
+    let color = read_color(&args, std::io::stdout().is_terminal());
+    let format = read_format(&args);
+    let clear = read_clear(&args);
+    let progress = read_progress(&args);
+    let (tx, rx) = event::make_channel(read_queue_size(&args)?);
+    #[cfg(all(unix, feature = "control"))]
+    let control = args
+        .control_socket
+        .as_deref()
+        .map(control::run)
+        .transpose()?;
+    #[cfg(not(all(unix, feature = "control")))]
+    if args.control_socket.is_some() {
+        eprintln!("control socket is not supported, it needs Unix and the control feature");
+    }
+    #[cfg(not(unix))]
+    if args.nice.is_some() {
+        eprintln!("warning: --nice is not supported on this platform, ignoring it");
+    }
+    let reporter_config = reporter::Config {
+        notify: !args.no_notify.unwrap_or_default(),
+        format,
+        history_file: args.history_file,
+        reset_stats: args.reset_stats.unwrap_or_default(),
+        color,
+        notify_on_change: args.notify_on_change.unwrap_or_default(),
+        notify_urgency: args.notify_urgency,
+        rich_notify: args.rich_notify.unwrap_or_default(),
+        once: args.once.unwrap_or_default(),
+        clear,
+        progress,
+        stderr_status: args.stderr_status.unwrap_or_default(),
+        timestamp_format: args
+            .timestamp_format
+            .unwrap_or(DEFAULT_TIMESTAMP_FORMAT.to_owned()),
+        metrics_addr: args.metrics_addr,
+        webhook: args.webhook,
+        webhook_on_fail_only: args.webhook_on_fail_only.unwrap_or_default(),
+        bell: args.bell.unwrap_or_default(),
+        bell_on_success: args.bell_on_success.unwrap_or_default(),
+        sound_success: args.sound_success,
+        sound_failure: args.sound_failure,
+        csv: args.csv,
+        step_names: configure_command
+            .iter()
+            .map(|_| watcher::CONFIGURE_STEP.to_owned())
+            .chain(
+                steps
+                    .iter()
+                    .filter(|(_, cmd)| !cmd.is_empty())
+                    .map(|(name, _)| name.clone()),
+            )
+            .collect(),
+        icon_success: args.icon_success,
+        icon_failure: args.icon_failure,
+        delta_threshold: args.delta_threshold.unwrap_or_default(),
+        delta_red_threshold: args.delta_red_threshold,
+        ratio_thresholds,
+        skip_duplicate_reports: args.skip_duplicate_reports.unwrap_or_default(),
+        #[cfg(all(unix, feature = "control"))]
+        control: control.clone(),
+    };
     let config = watcher::Config {
-        watch_dir: args.watch_dir,
-        build_dir: args.build_dir,
-        build_command: args.build_command,
-        test_command: args.test_command,
+        watch_dirs,
+        build_dir: args.build_dir.unwrap_or_default(),
+        create_build_dir: args.create_build_dir.unwrap_or_default(),
+        steps,
         delay,
-        tx,
+        step_delays,
+        min_interval: args.min_interval.map(Duration::from_secs),
+        extensions,
+        patterns: args.patterns.unwrap_or_default(),
+        gitignore: !args.no_gitignore.unwrap_or_default(),
+        ignore: args.ignore.unwrap_or_default(),
+        only: args.only.unwrap_or_default(),
+        build_files,
+        max_depth: args.max_depth,
+        follow_symlinks: args.follow_symlinks.unwrap_or_default(),
+        show_output: args.show_output.unwrap_or_default(),
+        stream: args.stream.unwrap_or_default(),
+        count_warnings,
+        warning_patterns: read_patterns(&args.warning_patterns, DEFAULT_WARNING_PATTERNS),
+        error_patterns: read_patterns(&args.error_patterns, DEFAULT_ERROR_PATTERNS),
+        initial_build: args.initial_build.unwrap_or_default(),
+        once: args.once.unwrap_or_default(),
+        max_builds: args.max_builds,
+        timeout: args.timeout.map(Duration::from_secs),
+        retries: args.retries.unwrap_or_default(),
+        continue_on_fail,
+        env,
+        nice: args.nice,
+        step_dirs,
+        triggers: args.triggers.unwrap_or_default(),
+        parallel_steps: args.parallel_steps.unwrap_or_default(),
+        pre_command: args.pre_command.filter(|cmd| !cmd.is_empty()),
+        post_command: args.post_command.filter(|cmd| !cmd.is_empty()),
+        run_command: args.run_command.filter(|cmd| !cmd.is_empty()),
+        configure_command: configure_command.clone(),
+        shell,
+        #[cfg(all(unix, feature = "control"))]
+        control,
     };
-    let watcher = watcher::run(config)?;
-    let reporter = reporter::run(rx)?;
-    let _ = tokio::join!(watcher, reporter);
+    if args.dry_run.unwrap_or_default() {
+        let plan = watcher::get_plan(config)?;
+        reporter::print_plan(format, &plan);
+        return Ok(());
+    }
+    let watcher = watcher::run(config, tx)?;
+    let reporter = reporter::run(reporter_config, rx)?;
+    let (watcher, status) = tokio::join!(watcher, reporter);
+    // the reporter has already printed the error of the watcher
+    if watcher.map_err(Error::other)?.is_err() {
+        std::process::exit(1);
+    }
+    if is_failed(status.ok().flatten(), args.once.unwrap_or_default()) {
+        std::process::exit(1);
+    }
     Ok(())
 }
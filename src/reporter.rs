@@ -1,73 +1,387 @@
+use clap::ValueEnum;
 use colored::{self, ColoredString, Colorize};
-use notify_rust::{Notification, Timeout};
-use std::collections::HashMap;
-use std::io::Result;
+use notify_rust::{Notification, Timeout, Urgency};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{Error, Result, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc::Receiver;
 use tokio::task::JoinHandle;
 
-use crate::event::ExecutorEvent;
+#[cfg(all(unix, feature = "control"))]
+use crate::control::Control;
+use crate::event::{self, ExecutorEvent, PlannedStep, StepData, WatchData};
+use crate::sync;
 
 const APP_NAME: &str = "CppWatch";
 const SHOW_TIMEOUT: u32 = 3000;
+const ICON_SUCCESS: &str = "emblem-checked";
+const ICON_FAILURE: &str = "emblem-error";
+const TREND_SIZE: usize = 20;
+// labels are padded to at least this width to keep the values aligned
+const LABEL_WIDTH: usize = 24;
+// the longest label of a step is "<name> duration stddev:"
+const LONGEST_STEP_LABEL: &str = " duration stddev:";
+const TREND_BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+const PROGRESS_FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
 
+#[derive(Clone, Serialize, Deserialize)]
 struct HistoricalData {
     time_total: Duration,
+    // running moments for min/max/stddev, so memory doesn't grow with builds
+    #[serde(default)]
+    samples: u64,
+    #[serde(default)]
+    time_min: Duration,
+    #[serde(default)]
+    time_max: Duration,
+    #[serde(default)]
+    time_sq_total: f64,
+}
+
+impl HistoricalData {
+    fn new(duration: Duration) -> HistoricalData {
+        let ms = duration.as_millis() as f64;
+        HistoricalData {
+            time_total: duration,
+            samples: 1,
+            time_min: duration,
+            time_max: duration,
+            time_sq_total: ms * ms,
+        }
+    }
+
+    fn add(&mut self, duration: Duration) {
+        let ms = duration.as_millis() as f64;
+        self.time_min = if self.samples == 0 {
+            duration
+        } else {
+            std::cmp::min(self.time_min, duration)
+        };
+        self.time_max = std::cmp::max(self.time_max, duration);
+        self.time_total += duration;
+        self.time_sq_total += ms * ms;
+        self.samples += 1;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    #[value(name = "full", alias = "text")]
+    #[serde(rename = "full", alias = "text")]
+    Text,
+    // a single line per build
+    Compact,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyUrgency {
+    Low,
+    Normal,
+    Critical,
+}
+
+pub struct Config {
+    pub notify: bool,
+    pub format: Format,
+    pub history_file: Option<PathBuf>,
+    // the saved history is ignored and overwritten by the next build
+    pub reset_stats: bool,
+    pub color: bool,
+    pub notify_on_change: bool,
+    // the urgency of every notification, by default failures are critical
+    // and the rest normal
+    pub notify_urgency: Option<NotifyUrgency>,
+    // the notification lists every step with its status and duration delta
+    pub rich_notify: bool,
+    pub once: bool,
+    pub clear: bool,
+    // a spinner with the running step is shown on stderr during a build
+    pub progress: bool,
+    // a line for every failed build on stderr as well, for when stdout is
+    // redirected
+    pub stderr_status: bool,
+    // strftime format of the local time shown in the report header, empty to
+    // hide it
+    pub timestamp_format: String,
+    // address to serve the counters on in Prometheus text format
+    pub metrics_addr: Option<String>,
+    // URL to POST the JSON report of every build to
+    pub webhook: Option<String>,
+    pub webhook_on_fail_only: bool,
+    // icon names or image paths used instead of the default icons
+    pub icon_success: Option<String>,
+    pub icon_failure: Option<String>,
+    pub bell: bool,
+    pub bell_on_success: bool,
+    // audio files played when a build finishes
+    pub sound_success: Option<PathBuf>,
+    pub sound_failure: Option<PathBuf>,
+    // file every finished build is appended to as a CSV row
+    pub csv: Option<PathBuf>,
+    // names of the configured steps, the CSV has a column for each one
+    pub step_names: Vec<String>,
+    // the duration delta (ms) is yellow above the first threshold and red
+    // above the second one
+    pub delta_threshold: u64,
+    pub delta_red_threshold: Option<u64>,
+    // the pass ratio (%) is yellow from the first threshold and green from
+    // the second one, red below
+    pub ratio_thresholds: (u64, u64),
+    // a failure identical to the previous one isn't shown again
+    pub skip_duplicate_reports: bool,
+    // the last report is shared with the control socket
+    #[cfg(all(unix, feature = "control"))]
+    pub control: Option<Control>,
+}
+
+#[derive(Serialize)]
+struct JsonStep<'a> {
+    name: &'a str,
+    duration_ms: u128,
+    status: &'static str,
+    exit_code: Option<i32>,
+    timed_out: bool,
+    warnings: Option<usize>,
+    errors: Option<usize>,
+    // the output of a failed step with --show-output
+    output: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct JsonStepSummary<'a> {
+    name: &'a str,
+    duration_avg_ms: u128,
+}
+
+#[derive(Serialize)]
+struct JsonSummary<'a> {
+    builds: u64,
+    passed: u64,
+    failed: u64,
+    ratio: u64,
+    steps: Vec<JsonStepSummary<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonError<'a> {
+    status: &'static str,
+    error: &'a str,
+}
+
+#[derive(Serialize)]
+struct JsonStarted<'a> {
+    status: &'static str,
+    watch_dirs: &'a [String],
+    steps: &'a [PlannedStep],
+    delay_ms: Option<u128>,
+    extensions: &'a [String],
+    patterns: &'a [String],
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    id: u64,
+    status: &'static str,
+    attempts: u32,
+    latency_ms: u128,
+    duration_ms: u128,
+    steps: Vec<JsonStep<'a>>,
 }
 
 type History = HashMap<String, HistoricalData>;
+
+// the counters are saved together with the history to keep the pass ratio
+// after a restart
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct SavedHistory {
+    pass_total: u64,
+    fail_total: u64,
+    history: History,
+}
+
 struct Context {
+    config: Config,
+    // set after the first failed notification, e.g. without a notification
+    // daemon, to not retry it on every build
+    notifier_failed: bool,
     pass_total: u64,
     fail_total: u64,
     history: History,
+    last_status: Option<bool>,
+    // total durations of the last builds, the oldest first
+    trend: VecDeque<Duration>,
+    // hash of the last build if it failed, see get_failure_hash
+    last_failure: Option<u64>,
 }
 
 impl Context {
-    fn new() -> Context {
+    fn new(config: Config) -> Context {
+        let saved = config
+            .history_file
+            .as_deref()
+            .filter(|_| !config.reset_stats)
+            .map(load_history)
+            .unwrap_or_default();
         Context {
-            pass_total: 0,
-            fail_total: 0,
-            history: History::new(),
+            config,
+            notifier_failed: false,
+            pass_total: saved.pass_total,
+            fail_total: saved.fail_total,
+            history: saved.history,
+            last_status: None,
+            trend: VecDeque::with_capacity(TREND_SIZE),
+            last_failure: None,
+        }
+    }
+
+    // the first build always counts as a change to establish a baseline
+    fn is_status_changed(&self, event: &ExecutorEvent) -> bool {
+        event
+            .get_build()
+            .map(|(success, _)| self.last_status != Some(success))
+            .unwrap_or(false)
+    }
+
+    fn is_duplicate(&self, event: &ExecutorEvent) -> bool {
+        self.config.skip_duplicate_reports
+            && get_failure_hash(event).is_some_and(|hash| self.last_failure == Some(hash))
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.config.history_file {
+            let saved = SavedHistory {
+                pass_total: self.pass_total,
+                fail_total: self.fail_total,
+                history: self.history.clone(),
+            };
+            if let Err(err) = save_history(path, &saved) {
+                eprintln!("failed to save history to {}: {}", path.display(), err);
+            }
         }
     }
 
     fn update(&mut self, event: &ExecutorEvent) {
-        let (success, (.., steps)) = match event {
-            ExecutorEvent::Fail(msg) => (false, msg),
-            ExecutorEvent::Success(msg) => (true, msg),
+        let Some((success, (_, steps, _, _))) = event.get_build() else {
+            return;
         };
 
         self.pass_total += success as u64;
         self.fail_total += !success as u64;
+        self.last_status = Some(success);
+        self.last_failure = get_failure_hash(event);
+
+        if self.trend.len() == TREND_SIZE {
+            self.trend.pop_front();
+        }
+        self.trend
+            .push_back(steps.iter().map(|step| step.get_duration()).sum());
 
         if success {
             for step in steps {
                 let duration = step.get_duration();
                 self.history
                     .entry(step.name.clone())
-                    .and_modify(|data| data.time_total += duration)
-                    .or_insert(HistoricalData {
-                        time_total: duration,
-                    });
+                    .and_modify(|data| data.add(duration))
+                    .or_insert(HistoricalData::new(duration));
             }
         }
     }
 
+    // steps don't always run together, so each one is averaged over its own
+    // runs; histories saved before the runs were counted use pass_total
     fn get_duration_avg(&self, name: &str) -> Option<Duration> {
-        let total = std::cmp::max(1, self.pass_total);
         self.history.get(name).map(|data| {
-            let ms = data.time_total.as_millis() / total as u128;
+            let samples = match data.samples {
+                0 => self.pass_total,
+                samples => samples,
+            };
+            let ms = data.time_total.as_millis() / std::cmp::max(1, samples) as u128;
             Duration::from_millis(ms as u64)
         })
     }
 
+    fn get_duration_min(&self, name: &str) -> Option<Duration> {
+        self.history
+            .get(name)
+            .filter(|data| data.samples > 0)
+            .map(|data| data.time_min)
+    }
+
+    fn get_duration_max(&self, name: &str) -> Option<Duration> {
+        self.history
+            .get(name)
+            .filter(|data| data.samples > 0)
+            .map(|data| data.time_max)
+    }
+
+    fn get_duration_stddev(&self, name: &str) -> Option<Duration> {
+        self.history
+            .get(name)
+            .filter(|data| data.samples > 0)
+            .map(|data| {
+                let samples = data.samples as f64;
+                let mean = data.time_total.as_millis() as f64 / samples;
+                let variance = (data.time_sq_total / samples - mean * mean).max(0.0);
+                Duration::from_millis(variance.sqrt() as u64)
+            })
+    }
+
+    fn get_step_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.history.keys().map(|x| x.as_str()).collect();
+        names.sort();
+        names
+    }
+
     fn get_ratio(&self) -> u64 {
         let total = std::cmp::max(self.pass_total + self.fail_total, 1);
         self.pass_total * 100 / total
     }
 }
 
+// A failed build is identified by the step it failed on and how, the durations
+// and the output may differ between the same failures. None for the passed
+// builds.
+fn get_failure_hash(event: &ExecutorEvent) -> Option<u64> {
+    let (success, (_, steps, _, _)) = event.get_build()?;
+    let step = get_failed_step(steps).filter(|_| !success)?;
+    let mut hasher = DefaultHasher::new();
+    (&step.name, step.exit_code, step.timed_out).hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+fn load_history(path: &Path) -> SavedHistory {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(_) => return SavedHistory::default(),
+    };
+    serde_json::from_str(&text).unwrap_or_else(|err| {
+        eprintln!("ignoring corrupted history {}: {}", path.display(), err);
+        SavedHistory::default()
+    })
+}
+
+fn save_history(path: &Path, saved: &SavedHistory) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let text = serde_json::to_string(saved)?;
+    fs::write(path, text)
+}
+
 fn status_as_str(status: bool) -> &'static str {
     match status {
         true => "done",
@@ -84,12 +398,23 @@ fn status_to_color_str(status: bool) -> ColoredString {
     }
 }
 
-fn ratio_to_color_str(ratio: u64) -> ColoredString {
+fn ratio_to_color_str(config: &Config, ratio: u64) -> ColoredString {
+    let (yellow, green) = config.ratio_thresholds;
     match ratio {
-        0..=49 => format!("{}", ratio).bright_red(),
-        50..=79 => format!("{}", ratio).bright_yellow(),
-        80..=100 => format!("{}", ratio).bright_green(),
-        _ => "Invalid".bright_red(),
+        _ if ratio > 100 => "Invalid".bright_red(),
+        _ if ratio >= green => format!("{}", ratio).bright_green(),
+        _ if ratio >= yellow => format!("{}", ratio).bright_yellow(),
+        _ => format!("{}", ratio).bright_red(),
+    }
+}
+
+// slowdowns up to the threshold are treated as noise
+fn delta_to_color_str(config: &Config, diff: i64) -> ColoredString {
+    let txt = format!("{}", diff);
+    match config.delta_red_threshold {
+        Some(threshold) if diff > threshold as i64 => txt.bright_red(),
+        _ if diff > config.delta_threshold as i64 => txt.bright_yellow(),
+        _ => txt.bright_green(),
     }
 }
 
@@ -101,106 +426,944 @@ fn duration_diff_as_millis(current: Duration, expect: Duration) -> i64 {
     }
 }
 
-fn get_icon_name(status: bool) -> &'static str {
+fn get_icon_name(config: &Config, status: bool) -> &str {
     if status {
-        "emblem-checked"
+        config.icon_success.as_deref().unwrap_or(ICON_SUCCESS)
     } else {
-        "emblem-error"
+        config.icon_failure.as_deref().unwrap_or(ICON_FAILURE)
     }
 }
 
-fn print_step_report(name: &str, duration: Duration, duration_avg: Duration) {
+// An icon given as a path has to exist, otherwise the notification shows a
+// broken image. Icon names can't be checked, they depend on the desktop theme.
+fn check_icon(icon: Option<String>) -> Option<String> {
+    let icon = icon?;
+    let is_path = icon.contains(std::path::MAIN_SEPARATOR) || icon.contains('/');
+    if is_path && !Path::new(&icon).is_file() {
+        eprintln!("icon {} not found, using the default one", icon);
+        return None;
+    }
+    Some(icon)
+}
+
+// long step names widen the label column of the whole report
+fn get_label_width<'a>(names: impl Iterator<Item = &'a str>) -> usize {
+    names
+        .map(|name| name.chars().count() + LONGEST_STEP_LABEL.len())
+        .fold(LABEL_WIDTH, std::cmp::max)
+}
+
+fn print_step_report(context: &Context, name: &str, duration: Duration, width: usize) {
+    let duration_avg = context
+        .get_duration_avg(name)
+        .unwrap_or(Duration::from_millis(0));
     let diff = duration_diff_as_millis(duration, duration_avg);
-    let txtdiff = format!("{}", diff);
-    let txtdiff = if diff <= 0 {
-        txtdiff.bright_green()
-    } else {
-        txtdiff.bright_yellow()
-    };
+    let txtdiff = delta_to_color_str(&context.config, diff);
     let prefix = format!("{} duration:", name);
-    let field = format!("{: <24}", prefix);
+    let field = format!("{: <width$}", prefix);
     println!("{} {} ms", field, duration.as_millis());
     let prefix = format!("{} duration avg:", name);
-    let field = format!("{: <24}", prefix);
+    let field = format!("{: <width$}", prefix);
     println!("{} {} ms", field, duration_avg.as_millis());
 
+    let stats = [
+        ("min", context.get_duration_min(name)),
+        ("max", context.get_duration_max(name)),
+        ("stddev", context.get_duration_stddev(name)),
+    ];
+    for (stat, value) in stats {
+        if let Some(value) = value {
+            let prefix = format!("{} duration {}:", name, stat);
+            let field = format!("{: <width$}", prefix);
+            println!("{} {} ms", field, value.as_millis());
+        }
+    }
+
     let prefix = format!("{} duration delta:", name);
-    let field = format!("{: <24}", prefix);
+    let field = format!("{: <width$}", prefix);
     println!("{} {} ms", field, txtdiff);
 }
 
+fn print_step_status(name: &str, status: bool, width: usize) {
+    let prefix = format!("{} status:", name);
+    println!("{: <width$} {}", prefix, status_to_color_str(status));
+}
+
+fn print_exit_code(name: &str, exit_code: Option<i32>, timed_out: bool, width: usize) {
+    let prefix = format!("{} exit code:", name);
+    let field = format!("{: <width$}", prefix);
+    match exit_code {
+        _ if timed_out => println!("{} {}", field, "timeout".bright_red()),
+        Some(code) => println!("{} {}", field, code.to_string().bright_red()),
+        None => println!("{} {}", field, "none".bright_red()),
+    }
+}
+
+fn print_diagnostics(name: &str, (warnings, errors): (usize, usize), width: usize) {
+    let prefix = format!("{} warnings:", name);
+    let count = warnings.to_string();
+    match warnings {
+        0 => println!("{: <width$} {}", prefix, count),
+        _ => println!("{: <width$} {}", prefix, count.yellow()),
+    }
+    let prefix = format!("{} errors:", name);
+    let count = errors.to_string();
+    match errors {
+        0 => println!("{: <width$} {}", prefix, count),
+        _ => println!("{: <width$} {}", prefix, count.bright_red()),
+    }
+}
+
+fn print_step_output(name: &str, output: &str) {
+    print_line();
+    println!("{} output:", name);
+    print_line();
+    print!("{}", output);
+    if !output.ends_with('\n') {
+        println!();
+    }
+}
+
+// bars are scaled between the shortest and the longest build
+fn make_sparkline(durations: &VecDeque<Duration>) -> String {
+    let min = durations.iter().min().copied().unwrap_or_default();
+    let max = durations.iter().max().copied().unwrap_or_default();
+    let range = (max - min).as_millis().max(1);
+    durations
+        .iter()
+        .map(|duration| {
+            let level = (*duration - min).as_millis() * (TREND_BARS.len() as u128 - 1) / range;
+            TREND_BARS[level as usize]
+        })
+        .collect()
+}
+
 fn print_line() {
     println!("========================================");
 }
 
+#[cfg(unix)]
+fn format_local_time(format: &str) -> Option<String> {
+    if format.is_empty() {
+        return None;
+    }
+    let format = std::ffi::CString::new(format).ok()?;
+    let mut buf = [0u8; 128];
+    // SAFETY: tm is filled by localtime_r before it's read, strftime writes at
+    // most buf.len() bytes and returns how many it wrote
+    let len = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return None;
+        }
+        libc::strftime(buf.as_mut_ptr().cast(), buf.len(), format.as_ptr(), &tm)
+    };
+    // 0 when the result doesn't fit, the buffer is undefined then
+    (len > 0).then(|| String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+// localtime_r and strftime are POSIX
+#[cfg(not(unix))]
+fn format_local_time(_: &str) -> Option<String> {
+    None
+}
+
 fn print_report(context: &Context, event: &ExecutorEvent) {
-    let (success, (id, steps)) = match event {
-        ExecutorEvent::Fail(msg) => (false, msg),
-        ExecutorEvent::Success(msg) => (true, msg),
+    let Some((success, (id, steps, attempts, triggered_at))) = event.get_build() else {
+        return;
     };
 
+    let width = get_label_width(steps.iter().map(|step| step.name.as_str()));
     print_line();
-    println!("Build {}", id);
+    match format_local_time(&context.config.timestamp_format) {
+        Some(time) => println!("Build {} at {}", id, time),
+        None => println!("Build {}", id),
+    }
     print_line();
     for step in steps.iter() {
-        let duration_avg = context
-            .get_duration_avg(&step.name)
-            .unwrap_or(Duration::from_millis(0));
         let duration = step.get_duration();
-        print_step_report(&step.name, duration, duration_avg);
+        print_step_report(context, &step.name, duration, width);
+        print_step_status(&step.name, step.status, width);
+        if let Some(diagnostics) = step.diagnostics {
+            print_diagnostics(&step.name, diagnostics, width);
+        }
+        if !step.status {
+            print_exit_code(&step.name, step.exit_code, step.timed_out, width);
+        }
         println!();
     }
-    let ratio_txt = ratio_to_color_str(context.get_ratio());
-
     println!(
-        "{: <24} {} % [{}/{}]",
+        "{: <width$} {} ms",
+        "Total duration:",
+        event::get_total_duration(steps).as_millis()
+    );
+    let ratio_txt = ratio_to_color_str(&context.config, context.get_ratio());
+    println!(
+        "{: <width$} {} % [{}/{}]",
         "Pass ratio:",
         ratio_txt,
         context.pass_total,
         context.pass_total + context.fail_total
     );
+    println!(
+        "{: <width$} {}",
+        "Duration trend:",
+        make_sparkline(&context.trend).bright_blue()
+    );
+    println!(
+        "{: <width$} {} ms",
+        "Reaction latency:",
+        event::get_latency(steps, *triggered_at).as_millis()
+    );
+    if *attempts > 1 {
+        println!("{: <width$} {}", "Attempts:", attempts);
+    }
+    print_line();
+    match get_failed_step(steps) {
+        Some(step) => println!("Status: {} ({})", status_to_color_str(success), step.name),
+        None => println!("Status: {}", status_to_color_str(success)),
+    }
+    print_line();
+
+    for step in steps
+        .iter()
+        .filter(|step| !step.status && !step.output.is_empty())
+    {
+        print_step_output(&step.name, &step.output);
+    }
+}
+
+fn print_abort_report(id: u64, data: &StepData) {
+    print_line();
+    println!("Build {}", id);
+    print_line();
+    let width = get_label_width([data.name.as_str()].into_iter());
+    print_exit_code(&data.name, data.exit_code, data.timed_out, width);
     print_line();
-    println!("Status: {}", status_to_color_str(success));
+    println!("Status: {}", "aborted".bright_red());
     print_line();
+    if !data.output.is_empty() {
+        print_step_output(&data.name, &data.output);
+    }
+}
+
+// the step that broke the build, the first one with continue_on_fail
+fn get_failed_step(steps: &[StepData]) -> Option<&StepData> {
+    steps.iter().find(|step| !step.status)
 }
 
-fn show_notification(event: &ExecutorEvent) {
-    let (success, (id, steps)) = match event {
-        ExecutorEvent::Fail(msg) => (false, msg),
-        ExecutorEvent::Success(msg) => (true, msg),
+fn exit_code_as_str(step: &StepData) -> String {
+    match step.exit_code {
+        _ if step.timed_out => "timeout".to_owned(),
+        Some(code) => code.to_string(),
+        None => "none".to_owned(),
+    }
+}
+
+// e.g. "BUILD 42 FAILED: Test (exit 1)" or "BUILD 43 ABORTED: Pre-command
+// (exit 2)", without colors as stderr is often captured separately
+fn print_stderr_status(event: &ExecutorEvent) {
+    let (id, status, step) = match event {
+        ExecutorEvent::Aborted((id, data)) => (*id, "ABORTED", Some(data)),
+        _ => match event.get_build() {
+            Some((false, (id, steps, _, _))) => (*id, "FAILED", get_failed_step(steps)),
+            _ => return,
+        },
     };
-    let mut total_dur = Duration::from_millis(0);
-    for step in steps.iter() {
-        total_dur += step.get_duration();
+    match step {
+        Some(step) if step.timed_out => {
+            eprintln!("BUILD {} {}: {} (timeout)", id, status, step.name)
+        }
+        Some(step) => eprintln!(
+            "BUILD {} {}: {} (exit {})",
+            id,
+            status,
+            step.name,
+            exit_code_as_str(step)
+        ),
+        None => eprintln!("BUILD {} {}", id, status),
+    }
+}
+
+// e.g. "#42 FAIL 3.2s Test exit code: 2"
+fn print_compact_report(event: &ExecutorEvent) {
+    let (id, status, steps) = match event {
+        ExecutorEvent::Aborted((id, data)) => (*id, "ABORT".bright_red(), vec![data.clone()]),
+        _ => match event.get_build() {
+            Some((true, (id, steps, _, _))) => (*id, "PASS".bright_green(), steps.clone()),
+            Some((false, (id, steps, _, _))) => (*id, "FAIL".bright_red(), steps.clone()),
+            None => return,
+        },
+    };
+    let duration = event::get_total_duration(&steps);
+    let mut line = format!("#{} {} {:.1}s", id, status.bold(), duration.as_secs_f64());
+    if let Some(step) = get_failed_step(&steps) {
+        line.push_str(&format!(
+            " {} exit code: {}",
+            step.name,
+            exit_code_as_str(step)
+        ));
+    }
+    let warnings: usize = steps
+        .iter()
+        .filter_map(|step| step.diagnostics)
+        .map(|(warnings, _)| warnings)
+        .sum();
+    if warnings > 0 {
+        line.push_str(&format!(" warnings: {}", warnings));
+    }
+    println!("{}", line);
+    // the output of the failed steps with --show-output follows as is
+    for step in steps
+        .iter()
+        .filter(|step| !step.status && !step.output.is_empty())
+    {
+        print!("{}", step.output);
+        if !step.output.ends_with('\n') {
+            println!();
+        }
+    }
+}
+
+fn make_json_step(step: &StepData) -> JsonStep<'_> {
+    JsonStep {
+        name: &step.name,
+        duration_ms: step.get_duration().as_millis(),
+        status: status_as_str(step.status),
+        exit_code: step.exit_code,
+        timed_out: step.timed_out,
+        warnings: step.diagnostics.map(|(warnings, _)| warnings),
+        errors: step.diagnostics.map(|(_, errors)| errors),
+        output: Some(step.output.as_str()).filter(|x| !step.status && !x.is_empty()),
+    }
+}
+
+fn make_json_report(event: &ExecutorEvent) -> Option<JsonReport<'_>> {
+    if let ExecutorEvent::Aborted((id, data)) = event {
+        return Some(JsonReport {
+            id: *id,
+            status: "aborted",
+            attempts: 1,
+            latency_ms: 0,
+            duration_ms: data.get_duration().as_millis(),
+            steps: vec![make_json_step(data)],
+        });
+    }
+    let (success, (id, steps, attempts, triggered_at)) = event.get_build()?;
+    Some(JsonReport {
+        id: *id,
+        status: status_as_str(success),
+        attempts: *attempts,
+        latency_ms: event::get_latency(steps, *triggered_at).as_millis(),
+        duration_ms: event::get_total_duration(steps).as_millis(),
+        steps: steps.iter().map(make_json_step).collect(),
+    })
+}
+
+fn print_json_report(event: &ExecutorEvent) {
+    if let Some(report) = make_json_report(event) {
+        println!("{}", serde_json::to_string(&report).unwrap());
+    }
+}
+
+fn print_start_report(data: &WatchData) {
+    let width = get_label_width(data.plan.iter().map(|step| step.name.as_str()));
+    print_line();
+    println!("Watching {}", data.watch_dirs.join(", ").bold());
+    print_line();
+    for step in data.plan.iter() {
+        let prefix = format!("{}:", step.name);
+        println!(
+            "{: <width$} {}",
+            prefix,
+            shell_words::join(&step.command).bright_blue()
+        );
+    }
+    if let Some(delay) = data.delay {
+        println!("{: <width$} {} s", "Delay:", delay.as_secs());
     }
-    let txt = format!(
-        "Build {} {} after {} sec.",
-        id,
-        status_as_str(success),
-        total_dur.as_secs()
+    match data.patterns.is_empty() {
+        true => println!("{: <width$} {}", "Extensions:", data.extensions.join(", ")),
+        false => println!("{: <width$} {}", "Patterns:", data.patterns.join(", ")),
+    }
+    print_line();
+}
+
+fn print_json_start_report(data: &WatchData) {
+    let report = JsonStarted {
+        status: "started",
+        watch_dirs: &data.watch_dirs,
+        steps: &data.plan,
+        delay_ms: data.delay.map(|delay| delay.as_millis()),
+        extensions: &data.extensions,
+        patterns: &data.patterns,
+    };
+    println!("{}", serde_json::to_string(&report).unwrap());
+}
+
+pub fn print_plan(format: Format, plan: &[PlannedStep]) {
+    match format {
+        Format::Text | Format::Compact => {
+            for step in plan.iter() {
+                println!("{}: {}", step.name, shell_words::join(&step.command));
+                println!("    in {}", step.dir);
+            }
+        }
+        Format::Json => println!("{}", serde_json::to_string(plan).unwrap()),
+    }
+}
+
+fn print_summary(context: &Context) {
+    let width = get_label_width(context.get_step_names().into_iter());
+    print_line();
+    println!("Summary");
+    print_line();
+    println!(
+        "{: <width$} {}",
+        "Builds total:",
+        context.pass_total + context.fail_total
+    );
+    println!(
+        "{: <width$} {} % [{}/{}]",
+        "Pass ratio:",
+        ratio_to_color_str(&context.config, context.get_ratio()),
+        context.pass_total,
+        context.pass_total + context.fail_total
     );
-    Notification::new()
+    for name in context.get_step_names() {
+        let duration_avg = context
+            .get_duration_avg(name)
+            .unwrap_or(Duration::from_millis(0));
+        let prefix = format!("{} duration avg:", name);
+        println!("{: <width$} {} ms", prefix, duration_avg.as_millis());
+    }
+    print_line();
+}
+
+fn print_json_summary(context: &Context) {
+    let summary = JsonSummary {
+        builds: context.pass_total + context.fail_total,
+        passed: context.pass_total,
+        failed: context.fail_total,
+        ratio: context.get_ratio(),
+        steps: context
+            .get_step_names()
+            .into_iter()
+            .map(|name| JsonStepSummary {
+                name,
+                duration_avg_ms: context
+                    .get_duration_avg(name)
+                    .unwrap_or(Duration::from_millis(0))
+                    .as_millis(),
+            })
+            .collect(),
+    };
+    println!("{}", serde_json::to_string(&summary).unwrap());
+}
+
+fn show_desktop_notification(
+    icon: &str,
+    txt: &str,
+    urgency: Urgency,
+) -> std::result::Result<(), String> {
+    let mut notification = Notification::new();
+    notification
         .summary(APP_NAME)
-        .icon(get_icon_name(success))
-        .body(&txt)
-        .timeout(Timeout::Milliseconds(SHOW_TIMEOUT)) //milliseconds
+        .icon(icon)
+        .body(txt)
+        .timeout(Timeout::Milliseconds(SHOW_TIMEOUT)); //milliseconds
+
+    // urgency is a hint of the XDG notification servers, the notifications
+    // go without it elsewhere
+    #[cfg(all(unix, not(target_os = "macos")))]
+    notification.urgency(urgency);
+    #[cfg(not(all(unix, not(target_os = "macos"))))]
+    let _ = urgency;
+    notification
         .show()
-        .unwrap();
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+// a failure shouldn't go unnoticed, critical notifications don't time out
+fn get_urgency(config: &Config, success: bool) -> Urgency {
+    match (config.notify_urgency, success) {
+        (Some(NotifyUrgency::Low), _) => Urgency::Low,
+        (Some(NotifyUrgency::Normal), _) | (None, true) => Urgency::Normal,
+        (Some(NotifyUrgency::Critical), _) | (None, false) => Urgency::Critical,
+    }
+}
+
+// a single line per step to fit into a notification, e.g. "✔ Build 1656 ms (+63 ms)"
+fn get_step_summary(context: &Context, step: &StepData) -> String {
+    let duration = step.get_duration();
+    let mark = if step.status { '✔' } else { '✘' };
+    let detail = match (step.status, step.exit_code) {
+        (true, _) => {
+            let avg = context.get_duration_avg(&step.name).unwrap_or_default();
+            format!("{:+} ms", duration_diff_as_millis(duration, avg))
+        }
+        _ if step.timed_out => "timeout".to_owned(),
+        (false, Some(code)) => format!("exit code {}", code),
+        (false, None) => "exit code none".to_owned(),
+    };
+    format!(
+        "{} {} {} ms ({})",
+        mark,
+        step.name,
+        duration.as_millis(),
+        detail
+    )
+}
+
+fn show_notification(context: &mut Context, event: &ExecutorEvent) {
+    if context.notifier_failed {
+        return;
+    }
+    let Some((success, (id, steps, _, _))) = event.get_build() else {
+        return;
+    };
+    let total_dur = event::get_total_duration(steps);
+    let mut txt = match get_failed_step(steps) {
+        Some(step) => format!(
+            "Build {} failed on {} after {} sec.",
+            id,
+            step.name,
+            total_dur.as_secs()
+        ),
+        None => format!(
+            "Build {} {} after {} sec.",
+            id,
+            status_as_str(success),
+            total_dur.as_secs()
+        ),
+    };
+    if context.config.rich_notify {
+        for step in steps.iter() {
+            txt.push('\n');
+            txt.push_str(&get_step_summary(context, step));
+        }
+    }
+    let icon = get_icon_name(&context.config, success);
+    let urgency = get_urgency(&context.config, success);
+    if let Err(err) = show_desktop_notification(icon, &txt, urgency) {
+        eprintln!(
+            "warning: failed to show notification, disabling them: {}",
+            err
+        );
+        context.notifier_failed = true;
+    }
+}
+
+// an audible cue in the terminal, independent from notifications
+fn ring_bell(config: &Config, success: bool) {
+    if (config.bell && !success) || (config.bell_on_success && success) {
+        eprint!("\x07");
+    }
+}
+
+#[cfg(feature = "sound")]
+fn play_sound(path: &Path) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (_stream, handle) = rodio::OutputStream::try_default()?;
+    let sink = rodio::Sink::try_new(&handle)?;
+    let file = fs::File::open(path)?;
+    sink.append(rodio::Decoder::new(std::io::BufReader::new(file))?);
+    sink.sleep_until_end();
+    Ok(())
+}
+
+#[cfg(not(feature = "sound"))]
+fn play_sound(_path: &Path) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    Ok(())
+}
+
+// played in the background unless it's a single run that exits right after
+fn play_sound_for(config: &Config, success: bool) {
+    let sound = if success {
+        &config.sound_success
+    } else {
+        &config.sound_failure
+    };
+    let Some(path) = sound.clone() else {
+        return;
+    };
+    let play = move || {
+        if let Err(err) = play_sound(&path) {
+            eprintln!("failed to play {}: {}", path.display(), err);
+        }
+    };
+    if config.once {
+        play();
+    } else {
+        tokio::task::spawn_blocking(play);
+    }
+}
+
+fn escape_csv(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+// build_id,timestamp,status followed by the duration of every step in ms,
+// the steps a failed build didn't reach are left empty
+fn append_csv(path: &Path, step_names: &[String], event: &ExecutorEvent) -> Result<()> {
+    let Some((success, (id, steps, _, _))) = event.get_build() else {
+        return Ok(());
+    };
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let mut text = String::new();
+    if file.metadata()?.len() == 0 {
+        let mut header = vec![
+            "build_id".to_owned(),
+            "timestamp".to_owned(),
+            "status".to_owned(),
+        ];
+        header.extend(
+            step_names
+                .iter()
+                .map(|name| escape_csv(&format!("{}_ms", name))),
+        );
+        text += &(header.join(",") + "\n");
+    }
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut row = vec![
+        id.to_string(),
+        timestamp.to_string(),
+        status_as_str(success).to_owned(),
+    ];
+    row.extend(step_names.iter().map(|name| {
+        steps
+            .iter()
+            .find(|step| &step.name == name)
+            .map(|step| step.get_duration().as_millis().to_string())
+            .unwrap_or_default()
+    }));
+    text += &(row.join(",") + "\n");
+    file.write_all(text.as_bytes())?;
+    file.flush()
+}
+
+fn save_csv(config: &Config, event: &ExecutorEvent) {
+    if let Some(path) = &config.csv {
+        if let Err(err) = append_csv(path, &config.step_names, event) {
+            eprintln!("failed to write {}: {}", path.display(), err);
+        }
+    }
+}
+
+// a missing file or a build without the sound feature disables the sound
+fn check_sound(sound: Option<PathBuf>) -> Option<PathBuf> {
+    let sound = sound?;
+    if !cfg!(feature = "sound") {
+        eprintln!("sounds are not supported, rebuild with the sound feature");
+        return None;
+    }
+    if !sound.is_file() {
+        eprintln!("sound {} not found", sound.display());
+        return None;
+    }
+    Some(sound)
+}
+
+// delivered in the background, a slow or broken endpoint doesn't hold the
+// reporter
+fn send_webhook(config: &Config, event: &ExecutorEvent) {
+    let Some(url) = config.webhook.clone() else {
+        return;
+    };
+    if config.webhook_on_fail_only && matches!(event, ExecutorEvent::Success(_)) {
+        return;
+    }
+    let Some(report) = make_json_report(event) else {
+        return;
+    };
+    let body = serde_json::to_string(&report).unwrap();
+    // a single run exits right after the report, so it can't be left behind
+    if config.once {
+        post_report(&url, &body);
+    } else {
+        tokio::task::spawn_blocking(move || post_report(&url, &body));
+    }
+}
+
+fn post_report(url: &str, body: &str) {
+    let response = ureq::post(url)
+        .set("Content-Type", "application/json")
+        .send_string(body);
+    if let Err(err) = response {
+        eprintln!("failed to send build report to {}: {}", url, err);
+    }
+}
+
+#[cfg(all(unix, feature = "control"))]
+fn update_control(config: &Config, event: &ExecutorEvent) {
+    if let (Some(control), Some(report)) = (&config.control, make_json_report(event)) {
+        *sync::lock(&control.status) = serde_json::to_value(report).unwrap_or_default();
+    }
+}
+
+fn process_shutdown(context: Arc<Mutex<Context>>) {
+    let context = sync::lock(&context);
+    match context.config.format {
+        Format::Text => print_summary(&context),
+        Format::Compact => println!(
+            "{} builds, {} % passed",
+            context.pass_total + context.fail_total,
+            context.get_ratio()
+        ),
+        Format::Json => print_json_summary(&context),
+    }
+}
+
+fn process_error(context: Arc<Mutex<Context>>, message: &str) {
+    match sync::lock(&context).config.format {
+        Format::Text | Format::Compact => eprintln!("{} {}", "error:".bright_red(), message),
+        Format::Json => {
+            let report = JsonError {
+                status: "error",
+                error: message,
+            };
+            println!("{}", serde_json::to_string(&report).unwrap());
+        }
+    }
+}
+
+fn process_start(context: Arc<Mutex<Context>>, data: &WatchData) {
+    match sync::lock(&context).config.format {
+        Format::Text => print_start_report(data),
+        Format::Compact => println!("Watching {}", data.watch_dirs.join(", ")),
+        Format::Json => print_json_start_report(data),
+    }
+}
+
+// an aborted build isn't counted in the stats
+fn process_abort(context: Arc<Mutex<Context>>, event: &ExecutorEvent, id: u64, data: &StepData) {
+    let context = sync::lock(&context);
+    match context.config.format {
+        Format::Text => {
+            if context.config.clear {
+                let _ = clearscreen::clear();
+            }
+            print_abort_report(id, data)
+        }
+        Format::Compact => print_compact_report(event),
+        Format::Json => print_json_report(event),
+    }
+    if context.config.stderr_status {
+        print_stderr_status(event);
+    }
+    ring_bell(&context.config, false);
+    play_sound_for(&context.config, false);
+    send_webhook(&context.config, event);
+    #[cfg(all(unix, feature = "control"))]
+    update_control(&context.config, event);
+}
+
+// the report, the notification and the sounds of a finished build
+fn show_report(context: &mut Context, event: &ExecutorEvent, changed: bool) {
+    match context.config.format {
+        Format::Text => {
+            if context.config.clear {
+                let _ = clearscreen::clear();
+            }
+            print_report(context, event)
+        }
+        Format::Compact => print_compact_report(event),
+        Format::Json => print_json_report(event),
+    }
+    if context.config.stderr_status {
+        print_stderr_status(event);
+    }
+    if context.config.notify && (changed || !context.config.notify_on_change) {
+        show_notification(context, event);
+    }
+    if let Some((success, _)) = event.get_build() {
+        ring_bell(&context.config, success);
+        play_sound_for(&context.config, success);
+    }
 }
 
 fn process_event(context: Arc<Mutex<Context>>, event: &ExecutorEvent) {
-    let mut context = context.lock().unwrap();
+    if let ExecutorEvent::Aborted((id, data)) = event {
+        return process_abort(context, event, *id, data);
+    }
+    let mut context = sync::lock(&context);
+    let changed = context.is_status_changed(event);
+    let duplicate = context.is_duplicate(event);
     context.update(event);
-    print_report(&context, event);
-    show_notification(event);
+    context.save();
+    if !duplicate {
+        show_report(&mut context, event, changed);
+    }
+    save_csv(&context.config, event);
+    send_webhook(&context.config, event);
+    #[cfg(all(unix, feature = "control"))]
+    update_control(&context.config, event);
 }
 
-pub fn run(mut rx: Receiver<ExecutorEvent>) -> Result<JoinHandle<()>> {
-    let context = Arc::new(Mutex::new(Context::new()));
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_metrics(context: &Context) -> String {
+    let mut out = String::new();
+    let counters = [
+        (
+            "builds_passed_total",
+            "Number of passed builds",
+            context.pass_total,
+        ),
+        (
+            "builds_failed_total",
+            "Number of failed builds",
+            context.fail_total,
+        ),
+    ];
+    for (name, help, value) in counters {
+        out += &format!("# HELP cppwatch_{} {}\n", name, help);
+        out += &format!("# TYPE cppwatch_{} counter\n", name);
+        out += &format!("cppwatch_{} {}\n", name, value);
+    }
+    out += "# HELP cppwatch_pass_ratio Percentage of passed builds\n";
+    out += "# TYPE cppwatch_pass_ratio gauge\n";
+    out += &format!("cppwatch_pass_ratio {}\n", context.get_ratio());
+    out += "# HELP cppwatch_step_duration_avg_seconds Average duration of a step\n";
+    out += "# TYPE cppwatch_step_duration_avg_seconds gauge\n";
+    for name in context.get_step_names() {
+        let duration_avg = context
+            .get_duration_avg(name)
+            .unwrap_or(Duration::from_millis(0));
+        out += &format!(
+            "cppwatch_step_duration_avg_seconds{{step=\"{}\"}} {}\n",
+            escape_label(name),
+            duration_avg.as_secs_f64()
+        );
+    }
+    out
+}
+
+// every request gets the metrics regardless of its method or path
+async fn serve_metrics_request(context: Arc<Mutex<Context>>, mut stream: TcpStream) {
+    let mut buf = [0; 1024];
+    if stream.read(&mut buf).await.is_err() {
+        return;
+    }
+    let body = format_metrics(&sync::lock(&context));
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+async fn serve_metrics(context: Arc<Mutex<Context>>, listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(serve_metrics_request(context.clone(), stream));
+            }
+            Err(err) => eprintln!("failed to accept metrics connection: {}", err),
+        }
+    }
+}
+
+// bound up front, so a busy or invalid address is reported at start
+fn bind_metrics(addr: &str) -> Result<TcpListener> {
+    let listener = std::net::TcpListener::bind(addr)
+        .map_err(|err| Error::new(err.kind(), format!("metrics address {}: {}", addr, err)))?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+// redrawn in place until the task is aborted
+async fn show_progress(name: String) {
+    let start_at = Instant::now();
+    for frame in PROGRESS_FRAMES.iter().cycle() {
+        let secs = start_at.elapsed().as_secs();
+        eprint!("\r\x1b[2K{} {} is running for {} s", frame, name, secs);
+        tokio::time::sleep(PROGRESS_INTERVAL).await;
+    }
+}
+
+fn stop_progress(progress: &mut Option<JoinHandle<()>>) {
+    if let Some(task) = progress.take() {
+        task.abort();
+        eprint!("\r\x1b[2K");
+    }
+}
+
+// the task returns the status of the last build, if any
+pub fn run(
+    mut config: Config,
+    mut rx: Receiver<ExecutorEvent>,
+) -> Result<JoinHandle<Option<bool>>> {
+    // the colored crate doesn't know about --color
+    colored::control::set_override(config.color);
+    config.icon_success = check_icon(config.icon_success);
+    config.icon_failure = check_icon(config.icon_failure);
+    config.sound_success = check_sound(config.sound_success);
+    config.sound_failure = check_sound(config.sound_failure);
+    let listener = config
+        .metrics_addr
+        .as_deref()
+        .map(bind_metrics)
+        .transpose()?;
+    let context = Arc::new(Mutex::new(Context::new(config)));
+    if let Some(listener) = listener {
+        tokio::spawn(serve_metrics(context.clone(), listener));
+    }
     let task = tokio::spawn(async move {
+        let mut progress = None;
+        let mut finished = false;
         while let Some(event) = rx.recv().await {
+            stop_progress(&mut progress);
+            if let ExecutorEvent::StepStarted(name) = event {
+                if sync::lock(&context).config.progress {
+                    progress = Some(tokio::spawn(show_progress(name)));
+                }
+                continue;
+            }
+            if let ExecutorEvent::Shutdown = event {
+                process_shutdown(context.clone());
+                finished = true;
+                break;
+            }
+            if let ExecutorEvent::Started(data) = &event {
+                process_start(context.clone(), data);
+                continue;
+            }
+            if let ExecutorEvent::Error(message) = &event {
+                process_error(context.clone(), message);
+                continue;
+            }
             process_event(context.clone(), &event);
+            if sync::lock(&context).config.once {
+                finished = true;
+                break;
+            }
+        }
+        // the watcher is gone without a shutdown, e.g. after an error
+        stop_progress(&mut progress);
+        if !finished && !sync::lock(&context).config.once {
+            process_shutdown(context.clone());
         }
+        let last_status = sync::lock(&context).last_status;
+        last_status
     });
 
     Ok(task)
@@ -0,0 +1,11 @@
+use std::sync::{Mutex, MutexGuard};
+
+// a panic while holding the lock shouldn't take the whole tool down, the
+// state is still usable for reporting
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| {
+        eprintln!("warning: recovering from a poisoned lock");
+        mutex.clear_poison();
+        err.into_inner()
+    })
+}
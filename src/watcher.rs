@@ -1,153 +1,756 @@
-use std::io::{Error, ErrorKind};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, PipeReader, Write};
 use std::mem;
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::{mpsc, Arc, Mutex, Weak};
+use std::thread;
 use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Sender;
+use tokio::sync::Notify;
 use tokio::task::{self, JoinHandle};
-use watchexec::action::{Action, Outcome, PreSpawn};
+use tracing::debug;
+use watchexec::action::{Action, Outcome, PostSpawn, PreSpawn};
 use watchexec::command::Command;
 use watchexec::config::{InitConfig, RuntimeConfig};
-use watchexec::Watchexec;
-use watchexec_events::{Event, ProcessEnd, Tag};
+use watchexec::error::{FsWatcherError, RuntimeError};
+use watchexec::{ErrorHook, Watchexec};
+use watchexec_events::{Event, Priority, ProcessEnd, Source, Tag};
+use watchexec_signals::Signal;
 
-use crate::event::{ExecutorEvent, StepData};
+#[cfg(all(unix, feature = "control"))]
+use crate::control::Control;
+use crate::event::{ExecutorEvent, PlannedStep, StepData, WatchData};
 use crate::filters;
+use crate::sync;
+use globset::GlobSet;
+use ignore::WalkBuilder;
+use regex::RegexSet;
+
+const CHANGED_FILES_ENV: &str = "CPPWATCH_CHANGED_FILES";
+// a process left running in the background by a step may keep its streamed
+// output open, it isn't waited for longer than that
+const STREAM_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+// the process of --run-command is killed if it doesn't exit on its own
+// after being asked to
+const SERVER_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+pub const CONFIGURE_STEP: &str = "Configure";
+const MARKER_KEY: &str = "cppwatch";
+// a step ran out of time
+const MARKER_TIMEOUT: &str = "timeout";
+// a single run has to stop after an aborted build
+const MARKER_ABORT: &str = "abort";
+// no changes came during the delay
+const MARKER_SETTLED: &str = "settled";
+// a build is requested over the control socket
+const MARKER_REBUILD: &str = "rebuild";
+// a parallel step completed
+const MARKER_PARALLEL: &str = "parallel";
+// directories of build artifacts that are never a reason to rebuild
+const ARTIFACT_GLOBS: &[&str] = &["**/CMakeFiles/**", "**/cmake-build-*/**"];
 
 #[derive(Clone)]
 pub struct Config {
-    pub watch_dir: String,
+    pub watch_dirs: Vec<String>,
     pub build_dir: String,
-    pub build_command: String,
-    pub test_command: String,
+    // a missing build dir is created instead of being an error
+    pub create_build_dir: bool,
+    // ordered list of (name, command) pairs executed one after another
+    pub steps: Vec<(String, String)>,
     pub delay: Option<Duration>,
-    pub tx: Sender<ExecutorEvent>,
+    // (step name, delay) pairs, such a step waits until no change came for
+    // its delay, e.g. to run expensive tests less often than the build
+    pub step_delays: Vec<(String, Duration)>,
+    // changes within this time after the start of the last build are dropped
+    pub min_interval: Option<Duration>,
+    pub extensions: Vec<String>,
+    // globs matching the sources instead of the extensions, relative ones are
+    // matched against the paths relative to the watch dirs
+    pub patterns: Vec<String>,
+    pub gitignore: bool,
+    pub ignore: Vec<String>,
+    // only changes under these paths trigger a build, relative ones are
+    // resolved against every watch dir, empty to watch everything
+    pub only: Vec<String>,
+    // names of build system files that trigger a build, empty to disable
+    pub build_files: Vec<String>,
+    // changes deeper than this many dirs below a watch dir are ignored
+    pub max_depth: Option<usize>,
+    // the targets of symlinked dirs are watched as well
+    pub follow_symlinks: bool,
+    pub show_output: bool,
+    // the captured output is printed as it comes as well
+    pub stream: bool,
+    // the output of the steps is captured to count the lines matching these
+    // patterns
+    pub count_warnings: bool,
+    pub warning_patterns: Vec<String>,
+    pub error_patterns: Vec<String>,
+    pub initial_build: bool,
+    pub once: bool,
+    // cppwatch exits after this many builds
+    pub max_builds: Option<u64>,
+    pub timeout: Option<Duration>,
+    pub env: Vec<(String, String)>,
+    // scheduling priority of the commands, from -20 (highest) to 19
+    pub nice: Option<i32>,
+    // (step name, working dir) pairs
+    pub step_dirs: Vec<(String, String)>,
+    // (step name, file name patterns) pairs, such a step runs only when one of
+    // the patterns matches a changed file, the other steps when a source or a
+    // build file changes
+    pub triggers: Vec<(String, Vec<String>)>,
+    // names of the steps spawned at the start of a build alongside the other
+    // ones, their results are joined into the same report
+    pub parallel_steps: Vec<String>,
+    // commands run before the first and after the last successful step,
+    // they aren't part of the build report
+    pub pre_command: Option<String>,
+    pub post_command: Option<String>,
+    // (re)started after every successful build and kept running until the
+    // next one, e.g. a server
+    pub run_command: Option<String>,
+    // runs before the first step when build files change, it's reported as
+    // a step of the build
+    pub configure_command: Option<String>,
+    // commands are passed as a single argument to this shell (e.g. ["sh", "-c"])
+    // instead of being executed directly, empty to execute them directly
+    pub shell: Vec<String>,
+    // how many times a failed build is started again before it's reported
+    pub retries: u32,
+    // run every step even after a failed one
+    pub continue_on_fail: bool,
+    #[cfg(all(unix, feature = "control"))]
+    pub control: Option<Control>,
 }
 
 impl Config {
+    // commands run in (and relative paths are resolved against) the first
+    // watch directory
+    fn get_watch_dir(&self) -> &str {
+        self.watch_dirs.first().map(|x| x.as_str()).unwrap_or(".")
+    }
+
+    // an absolute dir (e.g. /build, C:\build or \\server\share) is used as
+    // is, a relative one is appended to the watch dir
+    fn resolve_dir(&self, dir: &str) -> String {
+        let path = Path::new(self.get_watch_dir()).join(dir);
+        let path = path.canonicalize().unwrap_or(path);
+        path.to_string_lossy().into_owned()
+    }
+
     fn get_build_dir(&self) -> String {
         if self.build_dir.is_empty() {
-            self.watch_dir.clone()
+            self.get_watch_dir().to_owned()
         } else {
-            // silly check of absolute / relative path
-            if self.build_dir.starts_with('/') {
-                self.build_dir.clone()
-            } else {
-                // concat watch dir with relative build dir
-                format!("{}/{}", self.watch_dir, self.build_dir)
-            }
+            self.resolve_dir(&self.build_dir)
         }
     }
 
+    // steps run in the build dir unless their own dir is set
+    fn get_step_dir(&self, name: &str) -> &str {
+        self.step_dirs
+            .iter()
+            .find(|(step, _)| step == name)
+            .map(|(_, dir)| dir.as_str())
+            .unwrap_or(&self.build_dir)
+    }
+
+    // the same environment for the steps, the hooks and the run command, each
+    // in its own dir
+    fn prepare_command(&self, command: &mut tokio::process::Command, dir: &str) {
+        command.current_dir(dir);
+        command.envs(self.env.iter().map(|(key, value)| (key, value)));
+        set_nice(command, self.nice);
+    }
+
+    fn get_step_delay(&self, name: &str) -> Option<Duration> {
+        self.step_delays
+            .iter()
+            .find(|(step, _)| step == name)
+            .map(|(_, delay)| *delay)
+    }
+
+    fn is_parallel(&self, name: &str) -> bool {
+        self.parallel_steps.iter().any(|step| step == name)
+    }
+
+    // the commands the supervisor runs one after another
     fn get_commands(&self) -> Vec<Command> {
-        let mut cmds = vec![parse_command(&self.build_command).unwrap()];
-        if let Some(test_cmd) = parse_command(&self.test_command) {
-            cmds.push(test_cmd);
-        }
-        cmds
+        self.steps
+            .iter()
+            .filter(|(name, _)| !self.is_parallel(name))
+            .filter_map(|(_, cmd)| parse_command(cmd, &self.shell))
+            .collect()
     }
 }
 
+// a step handed over to the supervisor
+struct PendingStep {
+    id: u64,
+    run_num: u64,
+    index: usize,
+    // false until the process is actually spawned
+    spawned: bool,
+}
+
 struct Context {
     config: Config,
+    // steps of the current build in the order they were started
     steps: Vec<StepData>,
+    // steps waiting for their completion, they run one after another and
+    // complete in this order
+    pending: VecDeque<PendingStep>,
+    // all steps of a build are spawned for the same triggering events
+    trigger: Option<Arc<[Event]>>,
     task_num: u64,
-    steps_finished: usize,
-    steps_limit: usize,
+    // reported builds, retries and aborted builds included
+    builds_done: u64,
+    // every attempt of a build is a separate run
+    run_num: u64,
+    attempt: u32,
+    // the failed build is about to be started again
+    retrying: bool,
+    // newline separated paths that triggered the current build
+    changed_files: String,
+    // paths changed while waiting for the changes to settle
+    changes: Vec<String>,
+    build_files: GlobSet,
+    // the configure step of the current build if it ran
+    configure: Option<StepData>,
+    // the last change or request for a build
+    last_change: Instant,
+    // last_change of the current build, a retry is triggered by the failure
+    triggered_at: Instant,
+    // start of the last build, retries excluded
+    started_at: Option<Instant>,
+    step_num: u64,
+    // indices of the configured steps the current build runs, one after
+    // another and in parallel
+    active: Vec<usize>,
+    active_parallel: Vec<usize>,
+    // completed parallel steps of the current run and how many still run
+    parallel: Vec<StepData>,
+    parallel_pending: usize,
+    // (failed, index of the last step) once the steps run one after another
+    // are over, the build is reported when the parallel ones complete too
+    ended: Option<(bool, usize)>,
+    triggers: Vec<(String, GlobSet)>,
+    // (warning patterns, error patterns) with --count-warnings
+    diagnostics: Option<(RegexSet, RegexSet)>,
+    extensions: Vec<String>,
+    // (watch dirs, patterns) replacing the extensions
+    patterns: Option<(Vec<PathBuf>, GlobSet)>,
+    // applied with the active steps as its commands
+    runtime: Option<RuntimeConfig>,
+    // the current build is reported, steps still spawned for it are ignored
+    finished: bool,
+    timed_out: Option<u64>,
+    // the streamed output of the running steps by their ids, see --stream
+    streams: HashMap<u64, Stream>,
+    // the process of --run-command from the last successful build
+    server: Option<tokio::process::Child>,
+    // wakes the steps waiting for the previous one to be reported
+    step_done: Arc<Notify>,
+    // the watcher is exiting, no more steps are started
+    stopped: bool,
+    watcher: Weak<Watchexec>,
+    // the only sender of the reports, the channel is closed once the watcher
+    // is over even if some of its tasks are still around
+    tx: Option<Sender<ExecutorEvent>>,
 }
 
 impl Context {
-    fn new(config: Config) -> Context {
-        let steps_limit =
-            !config.build_command.is_empty() as usize + !config.test_command.is_empty() as usize;
+    fn new(config: Config, tx: Sender<ExecutorEvent>) -> Context {
+        let (active_parallel, active) =
+            (0..config.steps.len()).partition(|index| config.is_parallel(&config.steps[*index].0));
+        let build_files = filters::make_globset(&config.build_files).unwrap_or_default();
+        let triggers = config
+            .triggers
+            .iter()
+            .filter_map(|(name, patterns)| {
+                let globs = filters::make_globset(patterns).ok()?;
+                Some((name.clone(), globs))
+            })
+            .collect();
+        let diagnostics = config
+            .count_warnings
+            .then(|| make_diagnostics(&config).ok())
+            .flatten();
+        let extensions = filters::normalize_extensions(&config.extensions);
+        let patterns = (!config.patterns.is_empty())
+            .then(|| filters::make_globset(&config.patterns).ok())
+            .flatten()
+            .map(|globs| (get_canonical_watch_dirs(&config), globs));
 
         Context {
             config,
             steps: Vec::new(),
-            steps_finished: 0,
+            pending: VecDeque::new(),
+            trigger: None,
             task_num: 0,
-            steps_limit,
+            builds_done: 0,
+            run_num: 0,
+            attempt: 0,
+            retrying: false,
+            changed_files: String::new(),
+            changes: Vec::new(),
+            build_files,
+            configure: None,
+            last_change: Instant::now(),
+            triggered_at: Instant::now(),
+            started_at: None,
+            step_num: 0,
+            active,
+            active_parallel,
+            parallel: Vec::new(),
+            parallel_pending: 0,
+            ended: None,
+            triggers,
+            diagnostics,
+            extensions,
+            patterns,
+            runtime: None,
+            finished: true,
+            timed_out: None,
+            streams: HashMap::new(),
+            server: None,
+            step_done: Arc::new(Notify::new()),
+            stopped: false,
+            watcher: Weak::new(),
+            tx: Some(tx),
         }
     }
 
-    fn get_step_name(&self) -> String {
-        match self.steps.len() {
-            0 => "Build",
-            1 => "Test",
-            _ => "Unknown",
+    fn is_new_build(&self, events: &Arc<[Event]>) -> bool {
+        !self
+            .trigger
+            .as_ref()
+            .is_some_and(|trigger| Arc::ptr_eq(trigger, events))
+    }
+
+    // a step of the current run hasn't been reported as finished yet
+    fn is_run_pending(&self) -> bool {
+        self.pending.iter().any(|step| step.run_num == self.run_num)
+    }
+
+    fn is_step_running(&self, id: u64) -> bool {
+        self.pending
+            .front()
+            .is_some_and(|step| step.id == id && step.run_num == self.run_num)
+            && !self.is_over()
+    }
+
+    // no more steps are run for the current build, it may still wait for the
+    // parallel ones
+    fn is_over(&self) -> bool {
+        self.finished || self.ended.is_some()
+    }
+
+    fn get_step_name_at(&self, index: usize) -> String {
+        self.active
+            .get(index)
+            .and_then(|index| self.config.steps.get(*index))
+            .map(|(name, _)| name.as_str())
+            .unwrap_or("Unknown")
+            .to_owned()
+    }
+
+    fn is_too_soon(&self) -> bool {
+        match (self.config.min_interval, self.started_at) {
+            (Some(interval), Some(started_at)) => started_at.elapsed() < interval,
+            _ => false,
         }
-        .to_owned()
     }
 
-    fn start_step(&mut self) {
-        if self.steps.is_empty() {
-            self.task_num += 1;
+    fn is_source(&self, path: &Path) -> bool {
+        let source = match &self.patterns {
+            Some((roots, globs)) => filters::is_matching_path(path, roots, globs),
+            None => filters::has_extension(path, &self.extensions),
+        };
+        source || filters::is_build_path(path, &self.build_files)
+    }
+
+    // Steps with a trigger run when it matches a changed file, the others when
+    // a source changes. A build without changes runs every step.
+    fn select_steps(&self, paths: &[String]) -> Vec<usize> {
+        let paths: Vec<&Path> = paths.iter().map(Path::new).collect();
+        let mut selected = Vec::new();
+        for (index, (name, _)) in self.config.steps.iter().enumerate() {
+            if paths.is_empty() {
+                selected.push(index);
+                continue;
+            }
+            let trigger = self.triggers.iter().find(|(step, _)| step == name);
+            let fired = match trigger {
+                Some((_, globs)) => paths
+                    .iter()
+                    .find(|path| path.file_name().is_some_and(|name| globs.is_match(name))),
+                None => paths.iter().find(|path| self.is_source(path)),
+            };
+            if let Some(path) = fired {
+                debug!(step = name, ?path, "trigger fired");
+                selected.push(index);
+            }
+        }
+        selected
+    }
+
+    // Picks the steps of the next build, false if the changes trigger none of
+    // them. A running build keeps its steps.
+    fn activate_steps(&mut self, events: &[Event]) -> bool {
+        if self.triggers.is_empty() || !self.finished || self.retrying {
+            return true;
+        }
+        let mut paths = self.changes.clone();
+        collect_paths(&mut paths, events);
+        let (parallel, selected): (Vec<usize>, Vec<usize>) = self
+            .select_steps(&paths)
+            .into_iter()
+            .partition(|index| self.config.is_parallel(&self.config.steps[*index].0));
+        if selected.is_empty() {
+            self.changes.clear();
+            return false;
         }
-        let now = Instant::now();
-        let step = StepData {
-            status: false,
-            start_at: now,
-            stop_at: now,
-            name: self.get_step_name(),
+        self.active_parallel = parallel;
+        if selected == self.active {
+            return true;
+        }
+        let (Some(mut runtime), Some(watcher)) = (self.runtime.clone(), self.watcher.upgrade())
+        else {
+            return true;
         };
-        self.steps.push(step);
+        let commands = selected
+            .iter()
+            .filter_map(|index| self.config.steps.get(*index))
+            .filter_map(|(_, cmd)| parse_command(cmd, &self.config.shell))
+            .collect::<Vec<_>>();
+        runtime.commands(commands);
+        match watcher.reconfigure(runtime) {
+            Ok(()) => self.active = selected,
+            Err(err) => eprintln!("failed to select the steps: {}", err),
+        }
+        true
     }
 
-    fn finish_step(&mut self, status: bool) {
-        if self.steps.is_empty() {
-            return;
+    // A build starts once per trigger, before its pre-command. This is the
+    // only place build ids are assigned, so they have no gaps.
+    fn begin_build(&mut self, events: Arc<[Event]>) {
+        // a retry keeps the build id, a new build resets the attempts
+        if mem::take(&mut self.retrying) {
+            self.attempt += 1;
+            self.triggered_at = Instant::now();
+        } else {
+            self.triggered_at = self.last_change;
+            self.started_at = Some(Instant::now());
+            self.task_num += 1;
+            self.attempt = 1;
+            let mut paths = mem::take(&mut self.changes);
+            collect_paths(&mut paths, &events);
+            self.changed_files = paths.join("\n");
         }
+        debug!(
+            build = self.task_num,
+            attempt = self.attempt,
+            "starting a build"
+        );
+        self.run_num += 1;
+        self.steps.clear();
+        self.configure = None;
+        self.parallel.clear();
+        self.parallel_pending = 0;
+        self.ended = None;
+        self.finished = false;
+        self.trigger = Some(events);
+        // a step that failed to spawn never completes
+        self.pending.retain(|step| step.spawned);
+    }
+
+    // returns (step id, step index) of the started step
+    fn start_step(&mut self) -> (u64, usize) {
+        self.step_num += 1;
+        let index = self.steps.len();
+        if !self.is_over() {
+            let now = Instant::now();
+            let step = StepData {
+                status: false,
+                exit_code: None,
+                timed_out: false,
+                start_at: now,
+                stop_at: now,
+                name: self.get_step_name_at(index),
+                output: String::new(),
+                diagnostics: None,
+            };
+            self.steps.push(step);
+        }
+        self.pending.push_back(PendingStep {
+            id: self.step_num,
+            run_num: self.run_num,
+            index,
+            spawned: false,
+        });
+        (self.step_num, index)
+    }
+
+    fn confirm_step(&mut self) {
+        if let Some(step) = self.pending.back_mut() {
+            step.spawned = true;
+        }
+    }
+
+    fn is_output_captured(&self) -> bool {
+        self.config.show_output || self.config.stream || self.diagnostics.is_some()
+    }
 
-        if let Some(data) = self.steps.get_mut(self.steps_finished) {
+    fn get_output_path(&self, id: u64) -> PathBuf {
+        let name = format!("cppwatch-{}-{}.log", std::process::id(), id);
+        std::env::temp_dir().join(name)
+    }
+
+    // redirect stdout & stderr of the step into a log file
+    fn capture_output(
+        &mut self,
+        command: &mut tokio::process::Command,
+        id: u64,
+    ) -> Result<(), Error> {
+        if self.config.stream {
+            let stream = stream_output(command)?;
+            self.streams.insert(id, stream);
+            return Ok(());
+        }
+        let file = File::create(self.get_output_path(id))?;
+        command.stdout(Stdio::from(file.try_clone()?));
+        command.stderr(Stdio::from(file));
+        Ok(())
+    }
+
+    fn read_output(&mut self, id: u64) -> String {
+        if let Some(stream) = self.streams.remove(&id) {
+            return stream.wait();
+        }
+        let path = self.get_output_path(id);
+        let output = fs::read_to_string(&path).unwrap_or_default();
+        let _ = fs::remove_file(path);
+        output
+    }
+
+    // returns the status of the build once the whole pipeline is over
+    fn finish_step(&mut self, status: bool, exit_code: Option<i32>) -> Option<bool> {
+        let PendingStep {
+            id, run_num, index, ..
+        } = self.pending.pop_front()?;
+        self.step_done.notify_waiters();
+
+        let output = if self.is_output_captured() {
+            self.read_output(id)
+        } else {
+            String::new()
+        };
+        let (output, diagnostics) = self.check_output(output);
+
+        // a step spawned after its build had already failed
+        if run_num != self.run_num || self.is_over() {
+            debug!(id, run_num, "ignoring a step of a finished build");
+            return None;
+        }
+
+        let timed_out = self.timed_out == Some(id);
+        if let Some(data) = self.steps.get_mut(index) {
             data.stop_at = Instant::now();
             data.status = status;
+            data.exit_code = exit_code;
+            data.timed_out = timed_out;
+            data.output = output;
+            data.diagnostics = diagnostics;
         };
 
-        self.steps_finished += 1;
-        if status {
-            self.on_success();
+        // with continue_on_fail a failed step is reported after the last one
+        let last = index + 1 == self.active.len();
+        let failed = !status || (last && self.steps.iter().any(|step| !step.status));
+        let complete = last || !self.config.continue_on_fail;
+        if (failed && complete) || last {
+            self.ended = Some((failed, index));
+        }
+        self.complete_build()
+    }
+
+    // returns the status of the build once the whole pipeline is over, i.e.
+    // both its sequence of steps and its parallel steps
+    fn complete_build(&mut self) -> Option<bool> {
+        self.step_done.notify_waiters();
+        if self.parallel_pending > 0 {
+            return None;
+        }
+        let (failed, index) = self.ended.take()?;
+        let failed = failed || self.parallel.iter().any(|step| !step.status);
+        if failed && self.attempt <= self.config.retries {
+            self.on_retry();
+        } else if failed {
+            self.on_fail(index);
         } else {
-            self.on_fail();
+            self.on_success();
         }
+        (self.finished && !self.retrying).then_some(!failed)
     }
 
-    fn on_success(&mut self) {
-        if self.steps_finished == self.steps_limit {
-            let id = self.task_num;
-            let payload = self.take_steps();
-            let message = ExecutorEvent::Success((id, payload));
-            self.config.tx.try_send(message).unwrap();
-            self.reset();
+    // the output is kept only with --show-output, it's captured to count the
+    // warnings as well
+    fn check_output(&self, output: String) -> (String, Option<(usize, usize)>) {
+        let diagnostics = self
+            .diagnostics
+            .as_ref()
+            .map(|(warnings, errors)| count_diagnostics(&output, warnings, errors));
+        match self.config.show_output {
+            true => (output, diagnostics),
+            false => (String::new(), diagnostics),
         }
     }
 
-    fn on_fail(&mut self) {
+    // returns false for a step of another run
+    fn finish_parallel(&mut self, run_num: u64, mut data: StepData) -> bool {
+        if run_num != self.run_num || self.finished {
+            debug!(run_num, name = data.name, "ignoring a parallel step");
+            return false;
+        }
+        (data.output, data.diagnostics) = self.check_output(mem::take(&mut data.output));
+        self.parallel.push(data);
+        self.parallel_pending -= 1;
+        true
+    }
+
+    // the same environment for the steps of the supervisor and the parallel
+    // ones
+    fn prepare_command(&self, command: &mut tokio::process::Command, name: &str) {
+        let dir = self.config.get_step_dir(name);
+        self.config.prepare_command(command, dir);
+        command.env(CHANGED_FILES_ENV, &self.changed_files);
+    }
+
+    fn on_retry(&mut self) {
+        debug!(
+            build = self.task_num,
+            attempt = self.attempt,
+            "retrying the build"
+        );
+        self.steps.clear();
+        self.parallel.clear();
+        self.finished = true;
+        self.retrying = true;
+    }
+
+    fn on_success(&mut self) {
         let id = self.task_num;
         let payload = self.take_steps();
-        let message = ExecutorEvent::Fail((id, payload));
-        self.config.tx.try_send(message).unwrap();
-        self.reset();
+        let message = ExecutorEvent::Success((id, payload, self.attempt, self.triggered_at));
+        self.send(message);
+        self.finished = true;
+    }
+
+    // a build that ends before its first step
+    fn skip_build(&mut self) {
+        self.steps.clear();
+        self.trigger = None;
+        self.finished = true;
+    }
+
+    fn on_abort(&mut self, data: StepData) {
+        self.skip_build();
+        let message = ExecutorEvent::Aborted((self.task_num, data));
+        self.send(message);
+    }
+
+    fn on_configure_fail(&mut self, data: StepData) {
+        self.skip_build();
+        let payload = vec![data];
+        let message =
+            ExecutorEvent::Fail((self.task_num, payload, self.attempt, self.triggered_at));
+        self.send(message);
+    }
+
+    // configuring is needed when build files change, and for the builds
+    // without any changes (e.g. the initial one)
+    fn is_configure_needed(&self) -> bool {
+        self.changed_files.is_empty()
+            || self.changed_files.lines().any(|path| {
+                Path::new(path)
+                    .file_name()
+                    .is_some_and(|name| self.build_files.is_match(name))
+            })
+    }
+
+    fn on_fail(&mut self, index: usize) {
+        let id = self.task_num;
+        self.steps.truncate(index + 1);
+        let payload = self.take_steps();
+        let message = ExecutorEvent::Fail((id, payload, self.attempt, self.triggered_at));
+        self.send(message);
+        self.finished = true;
+    }
+
+    // counts a reported build, true when it's the last one of the session
+    fn count_build(&mut self) -> bool {
+        self.builds_done += 1;
+        self.config.once
+            || self
+                .config
+                .max_builds
+                .is_some_and(|max| self.builds_done >= max)
+    }
+
+    fn stop(&mut self) {
+        self.stopped = true;
+        self.step_done.notify_waiters();
+    }
+
+    // a single run stops the reporter after its build, otherwise it prints
+    // the summary on shutdown
+    fn end_session(&self) {
+        if !self.config.once {
+            self.send(ExecutorEvent::Shutdown);
+        }
+    }
+
+    // the reporter may lag behind, drop the report rather than kill the watcher
+    fn send(&self, message: ExecutorEvent) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        if let Err(err) = tx.try_send(message) {
+            eprintln!("failed to send build report: {}", err);
+        }
     }
 
     fn take_steps(&mut self) -> Vec<StepData> {
         let mut out = Vec::new();
         mem::swap(&mut self.steps, &mut out);
+        if let Some(configure) = self.configure.take() {
+            out.insert(0, configure);
+        }
+        // parallel steps follow the others in the configured order
+        let mut parallel = mem::take(&mut self.parallel);
+        parallel.sort_by_key(|step| {
+            self.config
+                .steps
+                .iter()
+                .position(|(name, _)| *name == step.name)
+        });
+        out.extend(parallel);
         out
     }
-
-    fn reset(&mut self) {
-        self.steps.clear();
-        self.steps_finished = 0;
-    }
 }
 
-fn get_command_result(event: &Event) -> Option<bool> {
+// returns the status of the finished command and its exit code if there is one
+fn get_command_result(event: &Event) -> Option<(bool, Option<i32>)> {
     for tag in event.tags.iter() {
         if let Tag::ProcessCompletion(res) = tag {
             let report = match res {
-                Some(ProcessEnd::Success) => Some(true),
-                Some(ProcessEnd::ExitError(_)) => Some(false),
-                Some(_) => Some(false),
-                None => None,
+                Some(ProcessEnd::Success) => Some((true, Some(0))),
+                Some(ProcessEnd::ExitError(code)) => Some((false, Some(code.get() as i32))),
+                // the status is unknown, e.g. the process was killed
+                Some(_) | None => Some((false, None)),
             };
             return report;
         };
@@ -155,8 +758,51 @@ fn get_command_result(event: &Event) -> Option<bool> {
     None
 }
 
-fn parse_command(input: &str) -> Option<Command> {
-    let mut splitted: Vec<String> = input.split(' ').map(|x| x.to_owned()).collect();
+fn collect_paths(paths: &mut Vec<String>, events: &[Event]) {
+    for (path, _) in events.iter().flat_map(|event| event.paths()) {
+        let path = path.to_string_lossy().into_owned();
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+}
+
+// Events the watcher sends to itself are told apart by the marker in their
+// metadata
+fn make_marker_event(marker: &str) -> Event {
+    Event {
+        tags: vec![Tag::Source(Source::Internal)],
+        metadata: HashMap::from([(MARKER_KEY.to_owned(), vec![marker.to_owned()])]),
+    }
+}
+
+fn is_marker(event: &Event, marker: &str) -> bool {
+    event
+        .metadata
+        .get(MARKER_KEY)
+        .is_some_and(|markers| markers.iter().any(|x| x == marker))
+}
+
+async fn send_marker(watcher: Option<Arc<Watchexec>>, marker: &str) {
+    if let Some(watcher) = watcher {
+        let _ = watcher
+            .send_event(make_marker_event(marker), Priority::Urgent)
+            .await;
+    }
+}
+
+fn parse_command(input: &str, shell: &[String]) -> Option<Command> {
+    if let Some((prog, args)) = shell.split_first() {
+        if input.trim().is_empty() {
+            return None;
+        }
+        let mut args = args.to_vec();
+        args.push(input.to_owned());
+        let prog = prog.clone();
+        return Some(Command::Exec { prog, args });
+    }
+    // shell-style tokenization: quotes and escaped spaces keep an argument whole
+    let mut splitted = shell_words::split(input).ok()?;
     if !splitted.is_empty() {
         let prog = splitted.remove(0);
         let args = splitted;
@@ -166,6 +812,176 @@ fn parse_command(input: &str) -> Option<Command> {
     }
 }
 
+// event paths are absolute, so are the dirs they are compared with
+fn get_canonical_watch_dirs(config: &Config) -> Vec<PathBuf> {
+    config
+        .watch_dirs
+        .iter()
+        .filter_map(|dir| Path::new(dir).canonicalize().ok())
+        .collect()
+}
+
+// Symlinked dirs outside of the watch dirs, they aren't watched by every
+// backend. Ignored dirs aren't walked.
+fn get_symlink_targets(watch_dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut targets: Vec<PathBuf> = Vec::new();
+    for watch_dir in watch_dirs.iter() {
+        let walker = WalkBuilder::new(watch_dir)
+            .hidden(false)
+            .require_git(false)
+            .follow_links(true)
+            .build();
+        for entry in walker.filter_map(|entry| entry.ok()) {
+            if !entry.path_is_symlink() || !entry.path().is_dir() {
+                continue;
+            }
+            let Ok(target) = entry.path().canonicalize() else {
+                continue;
+            };
+            let watched = watch_dirs
+                .iter()
+                .chain(targets.iter())
+                .any(|dir| target.starts_with(dir));
+            if !watched {
+                targets.push(target);
+            }
+        }
+    }
+    targets
+}
+
+fn get_only_dirs(config: &Config) -> Vec<PathBuf> {
+    let watch_dirs = get_canonical_watch_dirs(config);
+    let mut dirs = Vec::new();
+    for prefix in config.only.iter() {
+        for watch_dir in watch_dirs.iter() {
+            let dir = watch_dir.join(prefix);
+            let dir = dir.canonicalize().unwrap_or(dir);
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+    }
+    dirs
+}
+
+// A build dir nested in a watch dir would trigger a new build with every
+// artifact it gets. An in-source build (the build dir is a watch dir) can't be
+// excluded, its sources live there too.
+fn get_excluded_dirs(config: &Config) -> Vec<PathBuf> {
+    let watch_dirs = get_canonical_watch_dirs(config);
+    let Some(build_dir) = Path::new(&config.build_dir).canonicalize().ok() else {
+        return Vec::new();
+    };
+    let nested = watch_dirs
+        .iter()
+        .any(|dir| build_dir.starts_with(dir) && &build_dir != dir);
+    if nested {
+        vec![build_dir]
+    } else {
+        Vec::new()
+    }
+}
+
+// ENOSPC and EMFILE are what inotify returns when out of watches or instances
+fn is_watch_limit(err: &notify::Error) -> bool {
+    match &err.kind {
+        notify::ErrorKind::MaxFilesWatch => true,
+        notify::ErrorKind::Io(err) => matches!(err.raw_os_error(), Some(24) | Some(28)),
+        _ => false,
+    }
+}
+
+// Stopping a step may lose its exit status: the supervisor stops waiting for
+// the process group to handle the stop, and the abandoned wait reaps the
+// process. The supervisor then fails without reporting the completion, so it's
+// reported here as a killed step.
+fn is_lost_completion(error: &RuntimeError) -> bool {
+    matches!(
+        error,
+        RuntimeError::IoError {
+            about: "waiting on process group",
+            ..
+        }
+    )
+}
+
+// A step that failed to spawn never completes either. The supervisor gives up
+// on the rest of the build, so the step is reported as failed.
+fn is_spawn_failure(error: &RuntimeError) -> bool {
+    matches!(
+        error,
+        RuntimeError::ProcessDeadOnArrival
+            | RuntimeError::IoError {
+                about: "spawning process group" | "spawning process (ungrouped)",
+                ..
+            }
+    )
+}
+
+fn make_completion_event(end: Option<ProcessEnd>) -> Event {
+    Event {
+        tags: vec![Tag::Source(Source::Internal), Tag::ProcessCompletion(end)],
+        metadata: Default::default(),
+    }
+}
+
+// Hitting the inotify limits doesn't stop the watcher, changes in the
+// directories left without a watch are just never seen. Other errors are
+// reported by the handlers themselves (e.g. a failed pre-command).
+async fn on_error(
+    context: Arc<Mutex<Context>>,
+    hook: ErrorHook,
+) -> std::result::Result<(), Infallible> {
+    let completion = if is_lost_completion(&hook.error) {
+        debug!(error = %hook.error, "reporting the stopped step");
+        Some(make_completion_event(Some(ProcessEnd::ExitSignal(
+            Signal::ForceStop,
+        ))))
+    } else if is_spawn_failure(&hook.error) {
+        let mut lock = sync::lock(&context);
+        if let Some(step) = lock.pending.back() {
+            let name = lock.get_step_name_at(step.index);
+            eprintln!("failed to start {}: {}", name, hook.error);
+        }
+        lock.confirm_step();
+        Some(make_completion_event(None))
+    } else {
+        None
+    };
+    if let Some(event) = completion {
+        let watcher = sync::lock(&context).watcher.upgrade();
+        if let Some(watcher) = watcher {
+            let _ = watcher.send_event(event, Priority::Low).await;
+        }
+        return Ok(());
+    }
+
+    let limited = match &hook.error {
+        RuntimeError::FsWatcher { err, .. } => match err {
+            FsWatcherError::TooManyWatches(_) | FsWatcherError::TooManyHandles(_) => true,
+            FsWatcherError::PathAdd { err, .. } => is_watch_limit(err),
+            _ => false,
+        },
+        _ => false,
+    };
+    match &hook.error {
+        RuntimeError::FsWatcher { .. } if limited => eprintln!(
+            "warning: out of inotify watches, changes in some directories will \
+             be missed. Raise the limits, e.g. \
+             `sudo sysctl fs.inotify.max_user_watches=524288` and \
+             `sudo sysctl fs.inotify.max_user_instances=512`, or watch fewer \
+             directories"
+        ),
+        RuntimeError::FsWatcher { err, .. } => {
+            let message = format!("{}: {}", hook.error, err);
+            sync::lock(&context).send(ExecutorEvent::Error(message));
+        }
+        _ => debug!(error = %hook.error, "runtime error"),
+    }
+    Ok(())
+}
+
 fn is_dir_exists(path: &str) -> bool {
     std::fs::read_dir(path).is_ok()
 }
@@ -174,11 +990,64 @@ fn not_found_err(txt: &str) -> Error {
     Error::new(ErrorKind::NotFound, txt)
 }
 
+fn invalid_input_err(txt: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, txt)
+}
+
+fn check_commands(config: &Config) -> Result<(), Error> {
+    for (name, cmd) in config.steps.iter() {
+        if parse_command(cmd, &config.shell).is_none() {
+            let txt = format!("invalid {} command", name.to_lowercase());
+            return Err(invalid_input_err(&txt));
+        }
+    }
+    // the supervisor starts a build with its first step
+    if !config.steps.is_empty()
+        && config
+            .steps
+            .iter()
+            .all(|(name, _)| config.is_parallel(name))
+    {
+        return Err(invalid_input_err(
+            "at least one step has to run in sequence",
+        ));
+    }
+    let hooks = [
+        ("pre-command", &config.pre_command),
+        ("post-command", &config.post_command),
+        ("configure command", &config.configure_command),
+        ("run command", &config.run_command),
+    ];
+    for (name, cmd) in hooks {
+        if cmd
+            .as_deref()
+            .is_some_and(|cmd| parse_command(cmd, &config.shell).is_none())
+        {
+            return Err(invalid_input_err(&format!("invalid {}", name)));
+        }
+    }
+    Ok(())
+}
+
+fn is_broken_symlink(path: &str) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|meta| meta.is_symlink()) && fs::metadata(path).is_err()
+}
+
 fn check_dirs(config: &Config) -> Result<(), Error> {
-    if !is_dir_exists(&config.watch_dir) {
-        Err(not_found_err("invalid watch directory"))
+    if let Some(dir) = config.watch_dirs.iter().find(|dir| is_broken_symlink(dir)) {
+        Err(not_found_err(&format!(
+            "watch directory {} is a broken symlink",
+            dir
+        )))
+    } else if let Some(dir) = config.watch_dirs.iter().find(|dir| !is_dir_exists(dir)) {
+        Err(not_found_err(&format!("invalid watch directory {}", dir)))
     } else if !is_dir_exists(&config.build_dir) {
         Err(not_found_err("invalid build directory"))
+    } else if let Some((name, dir)) = config.step_dirs.iter().find(|(_, dir)| !is_dir_exists(dir)) {
+        Err(not_found_err(&format!(
+            "invalid {} directory {}",
+            name, dir
+        )))
     } else {
         Ok(())
     }
@@ -191,78 +1060,850 @@ async fn on_update(
 ) -> Result<(), Error> {
     let mut event_stop = false;
     let mut event_mods = false;
+    let mut event_timeout = false;
+    let mut event_abort = false;
+    let mut event_settled = false;
+    let mut event_rebuild = false;
+    let mut event_parallel = false;
     //let mut statuses = Vec::new();
     let mut process_status = None;
     for event in action.events.iter() {
         event_stop |= event.signals().filter(filters::is_stop_signal).count() > 0;
-        event_mods |= event.paths().count() > 0;
-        if let Some(value) = get_command_result(event) {
-            let exist = process_status.get_or_insert(value);
-            process_status = Some(*exist & value);
+        // an empty event is sent to trigger the initial build
+        event_mods |= event.paths().count() > 0 || event.is_empty();
+        event_timeout |= is_marker(event, MARKER_TIMEOUT);
+        event_abort |= is_marker(event, MARKER_ABORT);
+        event_settled |= is_marker(event, MARKER_SETTLED);
+        event_rebuild |= is_marker(event, MARKER_REBUILD);
+        event_parallel |= is_marker(event, MARKER_PARALLEL);
+        if let Some((value, code)) = get_command_result(event) {
+            let (exist, exist_code) = process_status.get_or_insert((value, code));
+            let code = if value { *exist_code } else { code };
+            process_status = Some((*exist & value, code));
         }
     }
+    debug!(
+        events = action.events.len(),
+        event_stop,
+        event_mods,
+        event_timeout,
+        event_abort,
+        event_settled,
+        event_rebuild,
+        event_parallel,
+        ?process_status,
+        "action"
+    );
+    if event_mods || event_rebuild {
+        sync::lock(&context).last_change = Instant::now();
+    }
 
     if event_stop {
+        debug!("stopping the watcher");
+        let mut context = sync::lock(&context);
+        context.send(ExecutorEvent::Shutdown);
+        context.stop();
         action.outcome(Outcome::Exit);
-    } else if event_mods {
-        if let Some(delay) = delay {
-            let task = [Outcome::Clear, Outcome::Sleep(delay), Outcome::Start].into_iter();
-            action.outcome(Outcome::if_running(
-                Outcome::DoNothing,
-                Outcome::sequence(task),
-            ));
-        } else {
-            let task = [Outcome::Clear, Outcome::Start].into_iter();
-            action.outcome(Outcome::if_running(
-                Outcome::DoNothing,
-                Outcome::sequence(task),
-            ));
-        };
-    } else if let Some(status) = process_status {
-        if !status {
-            action.outcome(Outcome::Stop);
+        return Ok(());
+    }
+    if event_abort {
+        let mut context = sync::lock(&context);
+        context.end_session();
+        context.stop();
+        action.outcome(Outcome::Exit);
+        return Ok(());
+    }
+
+    // a step may finish in the same batch as a change, it's reported first so
+    // the next step of its build isn't left waiting for it
+    let completion = match process_status.is_some() || event_parallel {
+        true => on_step_done(&context, process_status).await,
+        false => Completion::Continue,
+    };
+    let start = (event_mods || event_settled || event_rebuild)
+        && is_build_needed(&context, &action, event_mods, event_settled, delay);
+
+    match (completion, start) {
+        (Completion::Exit, _) => {
+            let mut context = sync::lock(&context);
+            context.end_session();
+            context.stop();
+            action.outcome(Outcome::Exit);
+        }
+        (Completion::Retry, _) | (Completion::Stop, true) => {
+            // stop what's left of the failed run before starting a build again
+            let task = [Outcome::Stop, Outcome::Start].into_iter();
+            action.outcome(Outcome::sequence(task));
+        }
+        (Completion::Stop, false) => action.outcome(Outcome::Stop),
+        (Completion::Continue, true) => {
+            debug!("starting a build unless one is running");
+            action.outcome(Outcome::if_running(Outcome::DoNothing, Outcome::Start));
         }
-        let mut context = context.lock().unwrap();
-        context.finish_step(status);
+        (Completion::Continue, false) if event_timeout => {
+            // the step is recorded as failed once its process is stopped
+            debug!("stopping the timed out step");
+            action.outcome(Outcome::if_running(Outcome::Stop, Outcome::DoNothing));
+        }
+        (Completion::Continue, false) => {}
     }
 
     Ok::<(), Error>(())
 }
 
+// what's left to do with the supervisor once a step is reported
+enum Completion {
+    Continue,
+    // the build failed, its remaining steps are not run
+    Stop,
+    Retry,
+    // the last build of the session is over
+    Exit,
+}
+
+async fn on_step_done(
+    context: &Arc<Mutex<Context>>,
+    process_status: Option<(bool, Option<i32>)>,
+) -> Completion {
+    let (done, retry, last, config) = {
+        let mut context = sync::lock(context);
+        let done = match process_status {
+            Some((status, exit_code)) => context.finish_step(status, exit_code),
+            None => context.complete_build(),
+        };
+        let last = done.is_some() && context.count_build();
+        (done, context.retrying, last, context.config.clone())
+    };
+    debug!(?process_status, ?done, "step finished");
+    if let Some(cmd) = config
+        .post_command
+        .as_deref()
+        .filter(|_| done == Some(true))
+    {
+        let data = run_hook(&config, "Post-command", cmd).await;
+        if !data.status {
+            let code = data.exit_code.map(|x| x.to_string());
+            eprintln!(
+                "post-command failed, exit code: {}",
+                code.as_deref().unwrap_or("none")
+            );
+            eprint!("{}", data.output);
+        }
+    }
+    if let Some(cmd) = config
+        .run_command
+        .as_deref()
+        .filter(|_| done == Some(true) && !last)
+    {
+        restart_server(context, &config, cmd).await;
+    }
+    if retry {
+        Completion::Retry
+    } else if last {
+        Completion::Exit
+    } else if process_status.is_some_and(|(status, _)| !status) && !config.continue_on_fail {
+        Completion::Stop
+    } else {
+        Completion::Continue
+    }
+}
+
+// false when the changes are dropped or wait to settle
+fn is_build_needed(
+    context: &Arc<Mutex<Context>>,
+    action: &Action,
+    event_mods: bool,
+    event_settled: bool,
+    delay: Option<Duration>,
+) -> bool {
+    if let (true, Some(delay)) = (event_mods, delay) {
+        // every change restarts the delay, the build starts once they settle
+        debug!(?delay, "waiting for changes to settle");
+        collect_paths(&mut sync::lock(context).changes, &action.events);
+        task::spawn(debounce(context.clone(), delay));
+        return false;
+    }
+    let mut context = sync::lock(context);
+    if context.parallel_pending > 0 {
+        debug!("dropping the changes, parallel steps are still running");
+        false
+    } else if (event_mods || event_settled) && context.is_too_soon() {
+        debug!("dropping the changes, the last build started too recently");
+        false
+    } else if !context.activate_steps(&action.events) {
+        debug!("no step is triggered by the changes");
+        false
+    } else {
+        true
+    }
+}
+
+async fn debounce(context: Arc<Mutex<Context>>, delay: Duration) {
+    tokio::time::sleep(delay).await;
+    let watcher = {
+        let context = sync::lock(&context);
+        // a later change has its own timer
+        if context.last_change.elapsed() < delay {
+            return;
+        }
+        context.watcher.upgrade()
+    };
+    send_marker(watcher, MARKER_SETTLED).await;
+}
+
+#[cfg(all(unix, feature = "control"))]
+async fn wait_rebuild(context: Arc<Mutex<Context>>, control: Control) {
+    loop {
+        control.rebuild.notified().await;
+        let watcher = sync::lock(&context).watcher.upgrade();
+        send_marker(watcher, MARKER_REBUILD).await;
+    }
+}
+
+async fn watch_timeout(context: Arc<Mutex<Context>>, id: u64, timeout: Duration) {
+    tokio::time::sleep(timeout).await;
+    let watcher = {
+        let mut context = sync::lock(&context);
+        if !context.is_step_running(id) {
+            return;
+        }
+        context.timed_out = Some(id);
+        context.watcher.upgrade()
+    };
+    send_marker(watcher, MARKER_TIMEOUT).await;
+}
+
+// the previous process is stopped first, so both don't compete e.g. for a
+// port, the new one runs in the build dir like the hooks
+async fn restart_server(context: &Arc<Mutex<Context>>, config: &Config, cmd: &str) {
+    let server = sync::lock(context).server.take();
+    if let Some(server) = server {
+        stop_server(server).await;
+    }
+    debug!(cmd, dir = config.build_dir, "starting the run command");
+    let result = match parse_command(cmd, &config.shell).map(|cmd| cmd.to_spawnable()) {
+        Some(Ok(mut command)) => {
+            config.prepare_command(&mut command, &config.build_dir);
+            command.kill_on_drop(true);
+            command.spawn()
+        }
+        Some(Err(err)) => Err(Error::other(err.to_string())),
+        None => Err(invalid_input_err("invalid run command")),
+    };
+    match result {
+        Ok(child) => sync::lock(context).server = Some(child),
+        Err(err) => eprintln!("run command failed: {}", err),
+    }
+}
+
+async fn stop_server(mut server: tokio::process::Child) {
+    debug!(pid = server.id(), "stopping the run command");
+    terminate(&mut server);
+    if tokio::time::timeout(SERVER_STOP_TIMEOUT, server.wait())
+        .await
+        .is_err()
+    {
+        let _ = server.kill().await;
+    }
+}
+
+// asks the process to exit, it may shut down gracefully on SIGTERM
+#[cfg(unix)]
+fn terminate(child: &mut tokio::process::Child) {
+    if let Some(pid) = child.id() {
+        // SAFETY: kill only does a syscall
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn terminate(child: &mut tokio::process::Child) {
+    let _ = child.start_kill();
+}
+
+// the priority is set in the child, raising it needs privileges and fails
+// the spawn otherwise
+#[cfg(unix)]
+fn set_nice(command: &mut tokio::process::Command, nice: Option<i32>) {
+    let Some(nice) = nice else {
+        return;
+    };
+    // SAFETY: setpriority only does a syscall, which is safe between fork and
+    // exec
+    unsafe {
+        command.pre_exec(
+            move || match libc::setpriority(libc::PRIO_PROCESS, 0, nice) {
+                0 => Ok(()),
+                _ => Err(Error::last_os_error()),
+            },
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn set_nice(_: &mut tokio::process::Command, _: Option<i32>) {}
+
+fn make_diagnostics(config: &Config) -> Result<(RegexSet, RegexSet), regex::Error> {
+    Ok((
+        RegexSet::new(&config.warning_patterns)?,
+        RegexSet::new(&config.error_patterns)?,
+    ))
+}
+
+// (warnings, errors) as the number of the matching lines
+fn count_diagnostics(output: &str, warnings: &RegexSet, errors: &RegexSet) -> (usize, usize) {
+    output.lines().fold((0, 0), |(warns, errs), line| {
+        (
+            warns + warnings.is_match(line) as usize,
+            errs + errors.is_match(line) as usize,
+        )
+    })
+}
+
+// The output of a step streamed to the terminal, a copy is kept for the
+// report and the diagnostics.
+struct Stream {
+    output: Arc<Mutex<Vec<u8>>>,
+    // disconnected once both stdout and stderr are over
+    done: mpsc::Receiver<()>,
+}
+
+impl Stream {
+    fn wait(self) -> String {
+        let _ = self.done.recv_timeout(STREAM_DRAIN_TIMEOUT);
+        String::from_utf8_lossy(&sync::lock(&self.output)).into_owned()
+    }
+}
+
+// forwards stdout & stderr of the command to the terminal line by line, so
+// the lines of the steps and of the reports don't mix
+fn stream_output(command: &mut tokio::process::Command) -> Result<Stream, Error> {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let (tx, done) = mpsc::channel();
+    let (reader, writer) = io::pipe()?;
+    command.stdout(writer);
+    let (copy, sender) = (output.clone(), tx.clone());
+    thread::spawn(move || forward_lines(reader, io::stdout(), copy, sender));
+    let (reader, writer) = io::pipe()?;
+    command.stderr(writer);
+    let copy = output.clone();
+    thread::spawn(move || forward_lines(reader, io::stderr(), copy, tx));
+    Ok(Stream { output, done })
+}
+
+fn forward_lines(
+    reader: PipeReader,
+    mut terminal: impl Write,
+    output: Arc<Mutex<Vec<u8>>>,
+    _done: mpsc::Sender<()>,
+) {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    while matches!(reader.read_until(b'\n', &mut line), Ok(n) if n > 0) {
+        let _ = terminal.write_all(&line).and_then(|_| terminal.flush());
+        sync::lock(&output).extend_from_slice(&line);
+        line.clear();
+    }
+}
+
+// the output is empty unless it's captured, with stream it's printed as it
+// comes as well
+async fn run_to_completion(
+    mut command: tokio::process::Command,
+    capture: bool,
+    stream: bool,
+) -> Result<(ExitStatus, String), Error> {
+    if capture && stream {
+        let stream = stream_output(&mut command)?;
+        let mut child = command.spawn()?;
+        // the command holds the other end of the pipes
+        drop(command);
+        let status = child.wait().await?;
+        Ok((status, stream.wait()))
+    } else if capture {
+        command.output().await.map(|out| {
+            let mut output = String::from_utf8_lossy(&out.stdout).into_owned();
+            output.push_str(&String::from_utf8_lossy(&out.stderr));
+            (out.status, output)
+        })
+    } else {
+        command.status().await.map(|status| (status, String::new()))
+    }
+}
+
+// runs a pre/post command to completion in the build dir, the output is
+// kept only with --show-output
+async fn run_hook(config: &Config, name: &str, cmd: &str) -> StepData {
+    debug!(name, cmd, dir = config.build_dir, "running hook");
+    let start_at = Instant::now();
+    let result = match parse_command(cmd, &config.shell).map(|cmd| cmd.to_spawnable()) {
+        Some(Ok(mut command)) => {
+            config.prepare_command(&mut command, &config.build_dir);
+            run_to_completion(command, config.show_output || config.stream, config.stream).await
+        }
+        Some(Err(err)) => Err(Error::other(err.to_string())),
+        None => Err(invalid_input_err(&format!(
+            "invalid {}",
+            name.to_lowercase()
+        ))),
+    };
+    let (status, exit_code, output) = match result {
+        // the streamed output is captured as well
+        Ok((status, _)) if !config.show_output => (status.success(), status.code(), String::new()),
+        Ok((status, output)) => (status.success(), status.code(), output),
+        Err(err) => (false, None, err.to_string()),
+    };
+    StepData {
+        status,
+        exit_code,
+        timed_out: false,
+        start_at,
+        stop_at: Instant::now(),
+        name: name.to_owned(),
+        output,
+        diagnostics: None,
+    }
+}
+
+// a failed pre-command aborts the build before its first step is spawned
+async fn run_pre_command(context: Arc<Mutex<Context>>) -> Result<(), Error> {
+    let config = sync::lock(&context).config.clone();
+    let Some(cmd) = config.pre_command.as_deref() else {
+        return Ok(());
+    };
+
+    let data = run_hook(&config, "Pre-command", cmd).await;
+    if data.status {
+        return Ok(());
+    }
+    let (watcher, last) = {
+        let mut lock = sync::lock(&context);
+        lock.on_abort(data);
+        (lock.watcher.upgrade(), lock.count_build())
+    };
+    if last {
+        send_marker(watcher, MARKER_ABORT).await;
+    }
+    Err(Error::other("pre-command failed"))
+}
+
+// a failed configure step fails the build before its first step is spawned
+async fn run_configure(context: Arc<Mutex<Context>>) -> Result<(), Error> {
+    let config = {
+        let lock = sync::lock(&context);
+        if !lock.is_configure_needed() {
+            return Ok(());
+        }
+        lock.config.clone()
+    };
+    let Some(cmd) = config.configure_command.as_deref() else {
+        return Ok(());
+    };
+
+    sync::lock(&context).send(ExecutorEvent::StepStarted(CONFIGURE_STEP.to_owned()));
+    let data = run_hook(&config, CONFIGURE_STEP, cmd).await;
+    let (watcher, last) = {
+        let mut lock = sync::lock(&context);
+        if data.status {
+            lock.configure = Some(data);
+            return Ok(());
+        }
+        lock.on_configure_fail(data);
+        (lock.watcher.upgrade(), lock.count_build())
+    };
+    if last {
+        send_marker(watcher, MARKER_ABORT).await;
+    }
+    Err(Error::other("configure step failed"))
+}
+
+// The supervisor spawns the next step right after the previous one exits,
+// before its completion is handled. Wait for it, so a failed build doesn't get
+// another step spawned.
+async fn wait_previous_step(
+    context: Arc<Mutex<Context>>,
+    events: &Arc<[Event]>,
+) -> Result<(), Error> {
+    let step_done = sync::lock(&context).step_done.clone();
+    loop {
+        // registered before checking, so a step reported meanwhile wakes it
+        let notified = step_done.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        {
+            let lock = sync::lock(&context);
+            if lock.stopped {
+                return Err(Error::other("watcher is stopped"));
+            }
+            if lock.is_new_build(events) {
+                return Ok(());
+            }
+            if !lock.is_run_pending() {
+                return match lock.is_over() {
+                    true => Err(Error::other("build is already finished")),
+                    false => Ok(()),
+                };
+            }
+        }
+        notified.await;
+    }
+}
+
 async fn on_start(context: Arc<Mutex<Context>>, prespawn: PreSpawn) -> Result<(), Error> {
     let mut command = prespawn.command().await.unwrap();
+    wait_previous_step(context.clone(), &prespawn.events).await?;
+    let new_build = {
+        let mut lock = sync::lock(&context);
+        let new_build = lock.is_new_build(&prespawn.events);
+        if new_build {
+            lock.begin_build(prespawn.events.clone());
+        }
+        new_build
+    };
+    if new_build {
+        run_pre_command(context.clone()).await?;
+        run_configure(context.clone()).await?;
+        start_parallel_steps(&context);
+    }
+    wait_step_delay(&context).await;
     {
-        let mut lock = context.lock().unwrap();
-        lock.start_step();
-        command.current_dir(&lock.config.watch_dir);
+        let mut lock = sync::lock(&context);
+        let (id, index) = lock.start_step();
+        if let Some(timeout) = lock.config.timeout {
+            task::spawn(watch_timeout(context.clone(), id, timeout));
+        }
+        let name = lock.get_step_name_at(index);
+        let dir = lock.config.get_step_dir(&name);
+        debug!(id, name, dir, command = %prespawn.command, "starting step");
+        lock.send(ExecutorEvent::StepStarted(name.clone()));
+        lock.prepare_command(&mut command, &name);
+        if lock.is_output_captured() {
+            lock.capture_output(&mut command, id)?;
+        }
     }
-    tokio::time::sleep(Duration::from_millis(100)).await;
     Ok::<(), Error>(())
 }
 
-pub fn run(mut config: Config) -> Result<JoinHandle<()>, Error> {
+// A step with its own delay waits until no change came for that time, changes
+// keep it waiting. The builds without changes don't wait.
+async fn wait_step_delay(context: &Arc<Mutex<Context>>) {
+    loop {
+        let remaining = {
+            let lock = sync::lock(context);
+            let name = lock.get_step_name_at(lock.steps.len());
+            let delay = lock.config.get_step_delay(&name);
+            match delay.filter(|_| !lock.changed_files.is_empty()) {
+                Some(delay) => delay.saturating_sub(lock.last_change.elapsed()),
+                None => return,
+            }
+        };
+        if remaining.is_zero() {
+            return;
+        }
+        debug!(?remaining, "waiting for changes to settle before the step");
+        tokio::time::sleep(remaining).await;
+    }
+}
+
+// the parallel steps of a build are spawned before its first step, they
+// aren't stopped when the build fails but it waits for them
+fn start_parallel_steps(context: &Arc<Mutex<Context>>) {
+    let mut lock = sync::lock(context);
+    for index in lock.active_parallel.clone() {
+        let (name, cmd) = lock.config.steps[index].clone();
+        let command = parse_command(&cmd, &lock.config.shell).map(|cmd| cmd.to_spawnable());
+        let Some(Ok(mut command)) = command else {
+            continue;
+        };
+        debug!(
+            name,
+            dir = lock.config.get_step_dir(&name),
+            cmd,
+            "starting parallel step"
+        );
+        lock.send(ExecutorEvent::StepStarted(name.clone()));
+        lock.prepare_command(&mut command, &name);
+        command.kill_on_drop(true);
+        lock.parallel_pending += 1;
+        let run_num = lock.run_num;
+        task::spawn(run_parallel_step(context.clone(), run_num, name, command));
+    }
+}
+
+async fn run_parallel_step(
+    context: Arc<Mutex<Context>>,
+    run_num: u64,
+    name: String,
+    command: tokio::process::Command,
+) {
+    let (capture, stream, timeout) = {
+        let lock = sync::lock(&context);
+        let config = &lock.config;
+        (lock.is_output_captured(), config.stream, config.timeout)
+    };
+    let start_at = Instant::now();
+    let run = run_to_completion(command, capture, stream);
+    // the process is killed once it's dropped
+    let result = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, run).await.ok(),
+        None => Some(run.await),
+    };
+    let (status, exit_code, timed_out, output) = match result {
+        Some(Ok((status, output))) => (status.success(), status.code(), false, output),
+        Some(Err(err)) => (false, None, false, err.to_string()),
+        None => (false, None, true, String::new()),
+    };
+    let data = StepData {
+        status,
+        exit_code,
+        timed_out,
+        start_at,
+        stop_at: Instant::now(),
+        name,
+        output,
+        diagnostics: None,
+    };
+    let watcher = {
+        let mut lock = sync::lock(&context);
+        if !lock.finish_parallel(run_num, data) {
+            return;
+        }
+        lock.watcher.upgrade()
+    };
+    send_marker(watcher, MARKER_PARALLEL).await;
+}
+
+async fn on_spawn(context: Arc<Mutex<Context>>) -> Result<(), Error> {
+    sync::lock(&context).confirm_step();
+    Ok::<(), Error>(())
+}
+
+// drops the disabled steps and makes the dirs absolute
+fn resolve_config(config: &mut Config) {
+    // an empty command disables the step
+    config.steps.retain(|(_, cmd)| !cmd.is_empty());
+    // absolute watch dirs keep the build dir and the reported paths the same
+    // wherever cppwatch is started from, a missing one is reported as is
+    config.watch_dirs = config
+        .watch_dirs
+        .iter()
+        .map(|dir| match Path::new(dir).canonicalize() {
+            Ok(path) => path.to_string_lossy().into_owned(),
+            Err(_) => dir.clone(),
+        })
+        .collect();
     config.build_dir = config.get_build_dir();
+    config.step_dirs = config
+        .step_dirs
+        .iter()
+        .map(|(name, dir)| (name.clone(), config.resolve_dir(dir)))
+        .collect();
+}
+
+fn get_args(command: &Command) -> Vec<String> {
+    match command {
+        Command::Exec { prog, args } => [prog.clone()].into_iter().chain(args.clone()).collect(),
+        command => vec![command.to_string()],
+    }
+}
+
+// the commands of a build in the order they run, hooks included
+pub fn get_plan(mut config: Config) -> Result<Vec<PlannedStep>, Error> {
+    resolve_config(&mut config);
+    check_commands(&config)?;
+    Ok(make_plan(&config))
+}
+
+fn make_plan(config: &Config) -> Vec<PlannedStep> {
+    let hook = |name: &str, cmd: &Option<String>| {
+        cmd.as_deref()
+            .and_then(|cmd| parse_command(cmd, &config.shell))
+            .map(|cmd| PlannedStep {
+                name: name.to_owned(),
+                command: get_args(&cmd),
+                dir: config.build_dir.clone(),
+            })
+    };
+    let mut plan = Vec::new();
+    plan.extend(hook("Pre-command", &config.pre_command));
+    plan.extend(hook(CONFIGURE_STEP, &config.configure_command));
+    for (name, cmd) in config.steps.iter() {
+        let Some(command) = parse_command(cmd, &config.shell) else {
+            continue;
+        };
+        plan.push(PlannedStep {
+            name: name.clone(),
+            command: get_args(&command),
+            dir: config.get_step_dir(name).to_owned(),
+        });
+    }
+    plan.extend(hook("Post-command", &config.post_command));
+    plan.extend(hook("Run", &config.run_command));
+    plan
+}
+
+pub fn run(
+    mut config: Config,
+    tx: Sender<ExecutorEvent>,
+) -> Result<JoinHandle<Result<(), Error>>, Error> {
+    resolve_config(&mut config);
+    if config.create_build_dir {
+        fs::create_dir_all(&config.build_dir).map_err(|err| {
+            Error::new(
+                err.kind(),
+                format!("failed to create build directory: {}", err),
+            )
+        })?;
+    }
     check_dirs(&config)?;
+    check_commands(&config)?;
 
-    let watch_dir = config.watch_dir.clone();
+    // resolved paths keep the events in line with the canonical dirs the
+    // filters compare them with
+    let watch_dirs = if config.follow_symlinks {
+        let mut dirs = get_canonical_watch_dirs(&config);
+        let targets = get_symlink_targets(&dirs);
+        debug!(?targets, "following symlinks");
+        dirs.extend(targets);
+        dirs
+    } else {
+        config.watch_dirs.iter().map(PathBuf::from).collect()
+    };
     let delay = config.delay;
+    // a single run doesn't need to watch anything
+    let initial_build = config.initial_build || config.once;
+    let watch_dirs = if config.once { Vec::new() } else { watch_dirs };
+    let started = (!config.once).then(|| WatchData {
+        watch_dirs: config.watch_dirs.clone(),
+        plan: make_plan(&config),
+        delay: config.delay,
+        extensions: config.extensions.clone(),
+        patterns: config.patterns.clone(),
+    });
 
     let mut runtime = RuntimeConfig::default();
-    runtime.pathset([watch_dir]);
+    runtime.pathset(watch_dirs);
     runtime.commands(config.get_commands());
 
-    let filter = Arc::new(filters::ExtenstionsFilter);
-    runtime.filterer(filter);
+    let excluded_dirs = get_excluded_dirs(&config);
+    debug!(?excluded_dirs, "excluding build directories");
+    let mut filter =
+        filters::ExtenstionsFilter::new(&config.extensions).with_excluded_dirs(excluded_dirs);
+    if !config.patterns.is_empty() {
+        let globs = filters::make_globset(&config.patterns)
+            .map_err(|err| invalid_input_err(&format!("invalid pattern: {}", err)))?;
+        filter = filter.with_patterns(get_canonical_watch_dirs(&config), globs);
+    }
+    let mut ignore = config.ignore.clone();
+    ignore.extend(ARTIFACT_GLOBS.iter().map(|x| x.to_string()));
+    let globs = filters::make_globset(&ignore)
+        .map_err(|err| invalid_input_err(&format!("invalid ignore pattern: {}", err)))?;
+    filter = filter.with_ignore(globs);
+    if !config.build_files.is_empty() {
+        let globs = filters::make_globset(&config.build_files)
+            .map_err(|err| invalid_input_err(&format!("invalid build file pattern: {}", err)))?;
+        filter = filter.with_build_files(globs);
+    }
+    if !config.triggers.is_empty() {
+        let patterns: Vec<String> = config
+            .triggers
+            .iter()
+            .flat_map(|(_, patterns)| patterns.iter().cloned())
+            .collect();
+        let globs = filters::make_globset(&patterns)
+            .map_err(|err| invalid_input_err(&format!("invalid trigger pattern: {}", err)))?;
+        filter = filter.with_trigger_files(globs);
+    }
+    if config.count_warnings {
+        make_diagnostics(&config)
+            .map_err(|err| invalid_input_err(&format!("invalid warning pattern: {}", err)))?;
+    }
+    if !config.only.is_empty() {
+        let only_dirs = get_only_dirs(&config);
+        debug!(?only_dirs, "watching only");
+        filter = filter.with_only_dirs(only_dirs);
+    }
+    if let Some(depth) = config.max_depth {
+        filter = filter.with_max_depth(get_canonical_watch_dirs(&config), depth);
+    }
+    filter = filter.with_watchignore(filters::GitIgnore::watchignore(&config.watch_dirs));
+    if config.gitignore {
+        filter = filter.with_gitignore(filters::GitIgnore::new(&config.watch_dirs));
+    }
+    runtime.filterer(Arc::new(filter));
 
-    let context = Arc::new(Mutex::new(Context::new(config)));
+    #[cfg(all(unix, feature = "control"))]
+    let control = config.control.clone();
+    let context = Arc::new(Mutex::new(Context::new(config, tx)));
+    #[cfg(all(unix, feature = "control"))]
+    if let Some(control) = control {
+        task::spawn(wait_rebuild(context.clone(), control));
+    }
+    let shared = context.clone();
     let local = context.clone();
+    let spawned = context.clone();
+    let failed = context.clone();
     runtime.on_pre_spawn(move |prespawn: PreSpawn| on_start(local.clone(), prespawn));
+    runtime.on_post_spawn(move |_: PostSpawn| on_spawn(spawned.clone()));
     runtime.on_action(move |action: Action| on_update(context.clone(), action, delay));
 
+    let mut init = InitConfig::default();
+    init.on_error(move |hook: ErrorHook| on_error(failed.clone(), hook));
+    {
+        // the steps of a build are selected by replacing the commands
+        let mut context = sync::lock(&shared);
+        if !context.triggers.is_empty() {
+            context.runtime = Some(runtime.clone());
+        }
+    }
+
     let task = task::spawn(async move {
-        let watcher = Watchexec::new(InitConfig::default(), runtime).unwrap();
-        watcher.main().await.unwrap().unwrap();
+        let result = watch(shared.clone(), init, runtime, started, initial_build).await;
+        let server = sync::lock(&shared).server.take();
+        if let Some(server) = server {
+            stop_server(server).await;
+        }
+        let mut context = sync::lock(&shared);
+        if let Err(err) = &result {
+            context.send(ExecutorEvent::Error(err.to_string()));
+        }
+        context.stop();
+        // the reporter stops once the channel is closed
+        context.tx = None;
+        result
     });
 
     Ok(task)
 }
+
+async fn watch(
+    context: Arc<Mutex<Context>>,
+    init: InitConfig,
+    runtime: RuntimeConfig,
+    started: Option<WatchData>,
+    initial_build: bool,
+) -> Result<(), Error> {
+    let watcher = Watchexec::new(init, runtime).map_err(Error::other)?;
+    {
+        let mut context = sync::lock(&context);
+        context.watcher = Arc::downgrade(&watcher);
+        if let Some(started) = started {
+            context.send(ExecutorEvent::Started(started));
+        }
+    }
+    if initial_build {
+        // queued before the main loop starts, so it's handled ahead of any
+        // file change
+        watcher
+            .send_event(Event::default(), Priority::Normal)
+            .await
+            .map_err(Error::other)?;
+    }
+    watcher
+        .main()
+        .await
+        .map_err(Error::other)?
+        .map_err(Error::other)
+}